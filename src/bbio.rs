@@ -1,37 +1,258 @@
 use serial::{SystemPort, Error, ErrorKind};
 use serial::core::Result;
-use std::io::{Read, Write};
+use failure;
+
 use super::i2c::I2CConn;
+use super::spi::SpiConn;
+use super::uart::UartConn;
+use super::onewire::OneWireConn;
+use super::rawwire::RawWireConn;
+use super::jtag::JtagConn;
+use super::info::{self, Capabilities, PirateInfo};
+use super::pirate;
+use super::transport::Transport;
+
+use std::io;
+use std::result;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum BinModeVSN {
     One
 }
 
-pub struct BBIOConn {
-    port: SystemPort,
-    pub vsn: BinModeVSN
+pub struct BBIOConn<T: Transport = SystemPort> {
+    port: T,
+    pub vsn: BinModeVSN,
+    /// The version info from the reset banner, read on the way in from
+    /// the terminal or by `read_info`.
+    pub info: Option<PirateInfo>,
+    inputs: Pins,
+    outputs: Pins
 }
 
-impl BBIOConn {
-    pub fn new(port: SystemPort, vsn: BinModeVSN) -> Self {
-        Self { port: port, vsn: vsn }
+impl<T: Transport> BBIOConn<T> {
+    pub fn new(port: T, vsn: BinModeVSN) -> Self {
+        // Entering bitbang mode leaves every pin as a (HiZ) input with
+        // the power supplies and pull-ups off.
+        Self { port: port,
+               vsn: vsn,
+               info: None,
+               inputs: Pins::IO,
+               outputs: Pins::empty() }
+    }
+
+    // Send a pin command and decode the pin state it answers with.
+    fn pin_call(&mut self, msg: &Message) -> Result<Pins> {
+        try!(self.port.write_all(&msg.send()));
+        let mut state = [0; 1];
+        try!(self.port.read_exact(&mut state));
+        Ok(Pins::from_bits_truncate(state[0]))
+    }
+
+    /// Set the direction of all five IO pins in one go: pins in
+    /// `inputs` become inputs, the rest outputs. Returns the pin state
+    /// the Pirate reports back.
+    pub fn configure_pins(&mut self, inputs: Pins) -> Result<Pins> {
+        let inputs = inputs & Pins::IO;
+        let state = try!(self.pin_call(&Message::ConfigurePinIO(inputs)));
+        self.inputs = inputs;
+        Ok(state)
+    }
+
+    /// Make `pins` inputs (`input` true) or outputs, leaving the
+    /// direction of the other pins alone.
+    pub fn set_direction(&mut self, pins: Pins, input: bool) -> Result<Pins> {
+        let mut inputs = self.inputs;
+        inputs.set(pins, input);
+        self.configure_pins(inputs)
+    }
+
+    /// Set every output, power and pull-up bit at once: members of
+    /// `on` are switched on, everything else off. Returns the pin
+    /// state the Pirate reports back.
+    pub fn set_pins(&mut self, on: Pins) -> Result<Pins> {
+        let state = try!(self.pin_call(&Message::SetOnOff(on)));
+        self.outputs = on;
+        Ok(state)
+    }
+
+    /// Switch `pins` on or off, leaving the others as they were.
+    pub fn set_pin(&mut self, pins: Pins, on: bool) -> Result<Pins> {
+        let mut outputs = self.outputs;
+        outputs.set(pins, on);
+        self.set_pins(outputs)
+    }
+
+    /// Read the current pin state without changing anything.
+    pub fn read_pins(&mut self) -> Result<Pins> {
+        let outputs = self.outputs;
+        self.set_pins(outputs)
+    }
+
+    /// Start PWM output on the AUX pin as close to `frequency` (in Hz)
+    /// and `duty` (0.0 - 1.0) as the timer allows, and return the
+    /// settings actually used.
+    ///
+    /// The PWM keeps running after leaving binary mode, and even after
+    /// this connection is gone. Only `clear_pwm` or a reset of the
+    /// Pirate stops it.
+    pub fn set_pwm(&mut self, frequency: f64, duty: f64) -> Result<PwmSettings> {
+        let settings = match PwmSettings::calculate(frequency, duty) {
+            Some(settings) => settings,
+            None => return Err(Error::new(ErrorKind::InvalidInput,
+                                          format!("can't generate PWM at {}Hz, {} duty",
+                                                  frequency, duty)))
+        };
+        try!(self.ack_call(&Message::SetupPWM(settings.prescaler,
+                                              settings.duty_register,
+                                              settings.period_register)));
+        Ok(settings)
+    }
+
+    /// Stop the PWM output on AUX.
+    pub fn clear_pwm(&mut self) -> Result<()> {
+        self.ack_call(&Message::DisablePWM)
+    }
+
+    /// Take one voltage probe reading, in volts.
+    pub fn probe_voltage(&mut self) -> Result<f64> {
+        try!(self.port.write_all(&Message::ProbeVoltage.send()));
+        let mut adc = [0; 2];
+        try!(self.port.read_exact(&mut adc));
+        Ok(adc_to_volts((adc[0] as u16) << 8 | adc[1] as u16))
+    }
+
+    /// Stream voltage probe readings as fast as the UART allows. The
+    /// Pirate keeps streaming until the returned iterator is dropped.
+    pub fn continuous_voltage(&mut self) -> Result<VoltageStream<'_, T>> {
+        try!(self.port.write_all(&Message::ContinuousVoltage.send()));
+        Ok(VoltageStream { conn: self, start: Instant::now(), failed: false })
+    }
+
+    /// Measure the frequency on the AUX pin, in Hz.
+    pub fn measure_frequency(&mut self) -> Result<u32> {
+        // The Pirate counts for a while before answering.
+        let original_timeout = self.port.timeout();
+        if original_timeout < FREQ_TIMEOUT {
+            try!(self.port.set_timeout(FREQ_TIMEOUT));
+        }
+        let mut count = [0; 4];
+        let res = self.port.write_all(&Message::MeasureFrequency.send())
+            .and_then(|_| self.port.read_exact(&mut count));
+        try!(self.port.set_timeout(original_timeout));
+        try!(res);
+        Ok((count[0] as u32) << 24 | (count[1] as u32) << 16 |
+           (count[2] as u32) << 8 | count[3] as u32)
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        info::capabilities(self.info.as_ref())
+    }
+
+    /// Reset the Pirate to read its version banner into `info`, then
+    /// come back to bitbang mode. This is a full hardware reset: the
+    /// power supplies, pull-ups, pins and PWM all go off.
+    pub fn read_info(&mut self) -> Result<&PirateInfo> {
+        let info = try!(pirate::read_info_with_reset(&mut self.port));
+        self.inputs = Pins::IO;
+        self.outputs = Pins::empty();
+        self.info = Some(info);
+        Ok(self.info.as_ref().unwrap())
+    }
+
+    /// Run the short or long self-test and report the error count. The
+    /// long test needs jumpers from +5V to Vpu and +3.3V to ADC, and
+    /// only the v2go and v3 have the test at all.
+    pub fn self_test(&mut self, long: bool) -> result::Result<SelfTestReport, failure::Error> {
+        try!(info::require(self.info.as_ref(), Capabilities::SELF_TEST, "self-test"));
+        let original_timeout = self.port.timeout();
+        if original_timeout < SELF_TEST_TIMEOUT {
+            try!(self.port.set_timeout(SELF_TEST_TIMEOUT));
+        }
+        let mut errors = [0; 1];
+        let res = self.port.write_all(&Message::SelfTest(long).send())
+            .and_then(|_| self.port.read_exact(&mut errors));
+        try!(self.port.set_timeout(original_timeout));
+        try!(res);
+        try!(self.ack_call(&Message::ExitSelfTest));
+        Ok(SelfTestReport { long: long, errors: errors[0] })
+    }
+
+    // Send a command that answers 0x01 on success.
+    fn ack_call(&mut self, msg: &Message) -> Result<()> {
+        try!(self.port.write_all(&msg.send()));
+        let mut reply = [0; 1];
+        try!(self.port.read_exact(&mut reply));
+        if reply[0] != 0x01 {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("got {:?} in reply to {:?}",
+                                          reply, msg.send())));
+        }
+        Ok(())
+    }
+
+    /// The pins currently configured as inputs.
+    pub fn inputs(&self) -> Pins {
+        self.inputs
+    }
+
+    /// The output, power and pull-up bits last written.
+    pub fn outputs(&self) -> Pins {
+        self.outputs
+    }
+
+    pub fn enter_i2c_mode(mut self) -> Result<I2CConn<T>> {
+        let info = self.info.take();
+        let port = try!(self.enter_mode(Message::I2C, "I2C"));
+        Ok(I2CConn::with_info(port, info))
+    }
+
+    pub fn enter_spi_mode(mut self) -> Result<SpiConn<T>> {
+        let info = self.info.take();
+        let port = try!(self.enter_mode(Message::SPI, "SPI"));
+        Ok(SpiConn::with_info(port, info))
+    }
+
+    pub fn enter_uart_mode(mut self) -> Result<UartConn<T>> {
+        let info = self.info.take();
+        let port = try!(self.enter_mode(Message::UART, "UART"));
+        Ok(UartConn::with_info(port, info))
+    }
+
+    pub fn enter_onewire_mode(mut self) -> Result<OneWireConn<T>> {
+        let info = self.info.take();
+        let port = try!(self.enter_mode(Message::OneWire, "1-Wire"));
+        Ok(OneWireConn::with_info(port, info))
+    }
+
+    pub fn enter_rawwire_mode(mut self) -> Result<RawWireConn<T>> {
+        let info = self.info.take();
+        let port = try!(self.enter_mode(Message::RawWire, "raw-wire"));
+        Ok(RawWireConn::with_info(port, info))
     }
 
-    pub fn enter_i2c_mode(self) -> Result<I2CConn> {
+    pub fn enter_jtag_mode(mut self) -> Result<JtagConn<T>> {
+        let info = self.info.take();
+        let port = try!(self.enter_mode(Message::OpenOCDJTAG, "OpenOCD JTAG"));
+        Ok(JtagConn::with_info(port, info))
+    }
+
+    fn enter_mode(self, msg: Message, name: &str) -> Result<T> {
         let mut port = self.port;
-        let msg = Message::I2C;
+        let good_reply = match msg.expect() {
+            Some(reply) => reply,
+            None => return Err(Error::new(ErrorKind::InvalidInput,
+                                          format!("{} isn't a binary mode", name)))
+        };
         try!(port.write_all(&msg.send()));
-        let good_reply = msg.expect();
-        use std::iter;
-        let mut buf = iter::repeat::<u8>(0)
-            .take(good_reply.len()).collect::<Vec<u8>>();
+        let mut buf = vec![0; good_reply.len()];
         try!(port.read_exact(&mut buf));
         if buf == good_reply {
-            return Ok(I2CConn::new(port))
+            return Ok(port)
         }
         Err(Error::new(ErrorKind::InvalidInput,
-                       "couldn't enter binary I2C mode"))
+                       format!("couldn't enter binary {} mode", name)))
     }
 }
 
@@ -68,7 +289,7 @@ impl BBIOConn {
 // 00000101 - Enter binary raw-wire mode, responds "RAW1"
 // Binary raw-wire mode is documented here.
 //
-// 00000110 - Enter OpenOCD JTAG mode
+// 00000110 - Enter OpenOCD JTAG mode, responds "OCD1"
 // OpenOCD mode is documented in the source only.
 //
 // 0000xxxx - Reserved for future raw protocol modes
@@ -143,16 +364,35 @@ pub enum Message {
     OpenOCDJTAG,
     Reserved(u8),
     ResetDevice,
-    SelfTest,
-    SetupPWM,
+    SelfTest(bool),
+    ExitSelfTest,
+    SetupPWM(Prescaler, u16, u16),
     DisablePWM,
     ProbeVoltage,
     ContinuousVoltage,
     MeasureFrequency,
-    ConfigurePinIO(bool, Pin),
-    SetOnOff(bool, OnOffItem)
+    ConfigurePinIO(Pins),
+    SetOnOff(Pins)
 }
 
+bitflags! {
+    /// The pin bits shared by the direction (010xxxxx) and on/off
+    /// (1xxxxxxx) commands and the pin state byte sent back for both.
+    pub struct Pins: u8 {
+        const CS     = 0b0000_0001;
+        const MISO   = 0b0000_0010;
+        const CLK    = 0b0000_0100;
+        const MOSI   = 0b0000_1000;
+        const AUX    = 0b0001_0000;
+        const PULLUP = 0b0010_0000;
+        const POWER  = 0b0100_0000;
+        /// The five pins that can be switched between input and output.
+        const IO     = Self::CS.bits | Self::MISO.bits | Self::CLK.bits
+                     | Self::MOSI.bits | Self::AUX.bits;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Pin {
     AUX,
     MOSI,
@@ -161,14 +401,167 @@ pub enum Pin {
     CS
 }
 
-pub enum OnOffItem {
-    AUX,
-    MOSI,
-    CLK,
-    MISO,
-    CS,
-    Power,
-    Pullup
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// Whether this was the long (jumpered) test.
+    pub long: bool,
+    pub errors: u8
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.errors == 0
+    }
+}
+
+const FREQ_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The probe has a 1/2 divider in front of the 10 bit, 3.3V ADC.
+pub fn adc_to_volts(adc: u16) -> f64 {
+    (adc as f64 / 1024.0) * 6.6
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct VoltageSample {
+    /// Time since the stream was started.
+    pub time: Duration,
+    pub adc: u16,
+    pub volts: f64
+}
+
+/// Yields samples until the first error (usually a timeout), which
+/// ends the stream.
+pub struct VoltageStream<'a, T: Transport + 'a> {
+    conn: &'a mut BBIOConn<T>,
+    start: Instant,
+    failed: bool
+}
+
+impl<'a, T: Transport> Iterator for VoltageStream<'a, T> {
+    type Item = Result<VoltageSample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let mut adc = [0; 2];
+        if let Err(e) = self.conn.port.read_exact(&mut adc) {
+            self.failed = true;
+            return Some(Err(e.into()));
+        }
+        let adc = (adc[0] as u16) << 8 | adc[1] as u16;
+        Some(Ok(VoltageSample { time: self.start.elapsed(),
+                                adc: adc,
+                                volts: adc_to_volts(adc) }))
+    }
+}
+
+impl<'a, T: Transport> Drop for VoltageStream<'a, T> {
+    fn drop(&mut self) {
+        // Any byte stops the stream. Let whatever was already on the
+        // way drain, then resync with a reset so the next command
+        // doesn't see stale samples.
+        let port = &mut self.conn.port;
+        if port.write_all(&Message::ResetProto.send()).is_err() {
+            return;
+        }
+        let mut buf = [0; 64];
+        loop {
+            match port.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break
+            }
+        }
+        let mut vsn = [0; 5];
+        let _ = port.write_all(&Message::ResetProto.send())
+            .and_then(|_| port.read_exact(&mut vsn));
+    }
+}
+
+// PWM runs off timer 2 at Fcy = Fosc/2 = 16MHz.
+const PWM_FCY: f64 = 16_000_000.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Prescaler {
+    Div1   = 0b00,
+    Div8   = 0b01,
+    Div64  = 0b10,
+    Div256 = 0b11
+}
+
+impl Prescaler {
+    pub fn divisor(&self) -> u32 {
+        match *self {
+            Prescaler::Div1 => 1,
+            Prescaler::Div8 => 8,
+            Prescaler::Div64 => 64,
+            Prescaler::Div256 => 256
+        }
+    }
+}
+
+/// PWM register values and the output they produce.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PwmSettings {
+    pub prescaler: Prescaler,
+    pub duty_register: u16,
+    pub period_register: u16,
+    /// The frequency actually generated, in Hz.
+    pub frequency: f64,
+    /// The duty cycle actually generated, 0.0 - 1.0.
+    pub duty: f64
+}
+
+impl PwmSettings {
+    /// Work out the register values for a PWM output, following the
+    /// PIC24F output compare manual:
+    ///
+    ///   period = (PR + 1) * prescaler / Fcy
+    ///   duty = OCR / (PR + 1)
+    ///
+    /// The smallest prescaler that fits the period into 16 bits is
+    /// used, as that gives the finest duty cycle steps. None if the
+    /// frequency can't be reached or the duty is outside 0.0 - 1.0.
+    pub fn calculate(frequency: f64, duty: f64) -> Option<Self> {
+        if frequency.is_nan() || frequency <= 0.0 || !(0.0..=1.0).contains(&duty) {
+            return None;
+        }
+        let prescalers = [Prescaler::Div1, Prescaler::Div8,
+                          Prescaler::Div64, Prescaler::Div256];
+        prescalers.iter()
+            .filter_map(|&prescaler| {
+                let ticks = (PWM_FCY / (prescaler.divisor() as f64 * frequency)).round();
+                // PR needs to be at least 1 to get any kind of square wave.
+                if !(2.0..=65536.0).contains(&ticks) {
+                    return None;
+                }
+                let duty_ticks = (duty * ticks).round();
+                Some(PwmSettings {
+                    prescaler: prescaler,
+                    duty_register: duty_ticks.min(65535.0) as u16,
+                    period_register: (ticks - 1.0) as u16,
+                    frequency: PWM_FCY / (prescaler.divisor() as f64 * ticks),
+                    duty: duty_ticks / ticks
+                })
+            })
+            .next()
+    }
+}
+
+impl From<Pin> for Pins {
+    fn from(pin: Pin) -> Self {
+        match pin {
+            Pin::AUX => Pins::AUX,
+            Pin::MOSI => Pins::MOSI,
+            Pin::CLK => Pins::CLK,
+            Pin::MISO => Pins::MISO,
+            Pin::CS => Pins::CS
+        }
+    }
 }
 
 use self::Message::*;
@@ -184,18 +577,34 @@ impl Message {
             OpenOCDJTAG => vec![0b00000110],
             Reserved(b) => vec![0b00001111 & b],
             ResetDevice => vec![0b00001111],
-            _ => unimplemented!()
+            SelfTest(long) => vec![0b0001_0000 | long as u8],
+            ExitSelfTest => vec![0xFF],
+            SetupPWM(prescaler, duty, period) => vec![0b00010010,
+                                                      prescaler as u8,
+                                                      (duty >> 8) as u8,
+                                                      duty as u8,
+                                                      (period >> 8) as u8,
+                                                      period as u8],
+            DisablePWM => vec![0b00010011],
+            ProbeVoltage => vec![0b00010100],
+            ContinuousVoltage => vec![0b00010101],
+            MeasureFrequency => vec![0b00010110],
+            ConfigurePinIO(inputs) => vec![0b0100_0000 | (inputs & Pins::IO).bits()],
+            SetOnOff(on) => vec![0b1000_0000 | on.bits()]
         }
     }
 
-    pub fn expect(&self) -> Vec<u8> {
+    /// The mode version string a mode switch answers with, None for
+    /// everything else.
+    pub fn expect(&self) -> Option<Vec<u8>> {
         match *self {
-            SPI  => vec![b'S', b'P', b'I', b'1'],
-            I2C  => vec![b'I', b'2', b'C', b'1'],
-            UART => vec![b'A', b'R', b'T', b'1'],
-            OneWire => vec![b'1', b'W', b'0', b'1'],
-            RawWire => vec![b'R', b'A', b'W', b'1'],
-            _ => unimplemented!()
+            SPI  => Some(vec![b'S', b'P', b'I', b'1']),
+            I2C  => Some(vec![b'I', b'2', b'C', b'1']),
+            UART => Some(vec![b'A', b'R', b'T', b'1']),
+            OneWire => Some(vec![b'1', b'W', b'0', b'1']),
+            RawWire => Some(vec![b'R', b'A', b'W', b'1']),
+            OpenOCDJTAG => Some(vec![b'O', b'C', b'D', b'1']),
+            _ => None
         }
     }
 }
@@ -2,9 +2,18 @@
 extern crate clap;
 
 extern crate ruspirate;
+extern crate ihex;
 
-use ruspirate::{Devices};
-use ruspirate::i2c::{PullUp, Speed, BusSettings};
+use std::cmp::min;
+use std::fs;
+use std::io;
+
+use ruspirate::{Device, Devices};
+use ruspirate::i2c::{I2CConn, PullUp, Speed, BusSettings, RESERVED_ADDRS};
+use ruspirate::eeprom::{Eeprom, Part};
+use ruspirate::spi;
+use ruspirate::spiflash::{Flash, Status};
+use ruspirate::info::Capabilities;
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 fn main() {
@@ -19,27 +28,113 @@ fn main() {
                             (@subcommand test =>
                              (about: "Test a buspirate")
                              (@arg dev: -d --dev +takes_value
-                              "The bus pirate device to use."))
+                              "The bus pirate device to use.")
+                             (@arg long: -l --long
+                              "Run the long self-test (needs +5V-Vpu and +3.3V-ADC jumpers)."))
                             (@subcommand vsn =>
                              (about: "Interrogate the version of the buspirate")
                              (@arg dev: -d --dev +takes_value
                               "The bus pirate device to use."))
+                            (@subcommand freq =>
+                             (about: "Measure the frequency on the AUX pin")
+                             (@arg dev: -d --dev +takes_value
+                              "The bus pirate device to use.")
+                             (@arg count: -n --count +takes_value
+                              "Number of samples to take, 0 to keep sampling. (default 1)")
+                             (@arg interval: -i --interval +takes_value
+                              "Milliseconds between samples. (default 1000)"))
                             (@subcommand i2c =>
                              (about: "I2C commands")
                              (@arg dev: -d --dev +takes_value
                               "The bus pirate device to use.")
                              (@arg voltage: -v --voltage
                               +takes_value
-                              "The bus voltage to use. (5, 3.3, 0 if not specified)")
+                              "Switch on the pull-ups at this voltage, v4 only. (5, 3.3; off if not specified)")
                              (@arg speed: -s --speed
                               +takes_value
                               "The bus speed to use (in Hz). (400k, 100k, 50k, 5k)")
+                             (@arg power: -p --power
+                              "Switch on the power supplies.")
                              (@arg dryrun: -r --("dry-run")
                               "Don't actually execute the command.")
                              (@subcommand scan =>
-                              (about: "Scan the i2c bus for r/w addresses"))
+                              (about: "Scan the i2c bus for r/w addresses")
+                              (@arg all: -a --all
+                               "Also probe the reserved addresses (0x00-0x07, 0x78-0x7f)."))
                              (@subcommand test =>
                               (about: "Test setting up binary i2c mode"))
+                             (@subcommand eeprom =>
+                              (about: "Read, write and verify 24Cxx EEPROMs")
+                              (@setting SubcommandRequiredElseHelp)
+                              (@arg part: -p --part +takes_value +required
+                               "The EEPROM part. (24c01 ... 24c1024)")
+                              (@arg pins: -a --("addr-pins") +takes_value
+                               "The A2-A0 pin strapping, 0-7. (default 0)")
+                              (@subcommand read =>
+                               (about: "Read the EEPROM into a file")
+                               (@arg file: +required
+                                "Output file, Intel HEX if it ends in .hex")
+                               (@arg offset: -o --offset +takes_value
+                                "First byte to read. (default 0)")
+                               (@arg len: -l --len +takes_value
+                                "Number of bytes to read. (default to the end)"))
+                              (@subcommand write =>
+                               (about: "Write a file to the EEPROM and verify it")
+                               (@arg file: +required
+                                "Input file, Intel HEX if it ends in .hex")
+                               (@arg offset: -o --offset +takes_value
+                                "Added to the file's addresses. (default 0)")
+                               (@arg noverify: -n --("no-verify")
+                                "Don't read the data back afterwards."))
+                              (@subcommand verify =>
+                               (about: "Compare the EEPROM with a file")
+                               (@arg file: +required
+                                "Input file, Intel HEX if it ends in .hex")
+                               (@arg offset: -o --offset +takes_value
+                                "Added to the file's addresses. (default 0)")))
+                            )
+                            (@subcommand spi =>
+                             (about: "SPI commands")
+                             (@setting SubcommandRequiredElseHelp)
+                             (@arg dev: -d --dev +takes_value
+                              "The bus pirate device to use.")
+                             (@arg speed: -s --speed +takes_value
+                              "The bus speed to use (in Hz). (30k ... 8M, default 1M)")
+                             (@arg power: -p --power
+                              "Switch on the power supplies.")
+                             (@subcommand flash =>
+                              (about: "Read, write, erase and verify SPI NOR flash")
+                              (@setting SubcommandRequiredElseHelp)
+                              (@arg unprotect: -u --unprotect
+                               "Clear the block protect bits before writing or erasing.")
+                              (@subcommand read =>
+                               (about: "Read the flash into a file")
+                               (@arg file: +required
+                                "Output file, Intel HEX if it ends in .hex")
+                               (@arg offset: -o --offset +takes_value
+                                "First byte to read. (default 0)")
+                               (@arg len: -l --len +takes_value
+                                "Number of bytes to read. (default to the end)"))
+                              (@subcommand write =>
+                               (about: "Write a file to the flash and verify it")
+                               (@arg file: +required
+                                "Input file, Intel HEX if it ends in .hex")
+                               (@arg offset: -o --offset +takes_value
+                                "Added to the file's addresses. (default 0)")
+                               (@arg noverify: -n --("no-verify")
+                                "Don't read the data back afterwards."))
+                              (@subcommand erase =>
+                               (about: "Erase the flash")
+                               (@arg offset: -o --offset +takes_value
+                                "First byte to erase. (default 0)")
+                               (@arg len: -l --len +takes_value
+                                "Number of bytes to erase. (default to the end)"))
+                              (@subcommand verify =>
+                               (about: "Compare the flash with a file")
+                               (@arg file: +required
+                                "Input file, Intel HEX if it ends in .hex")
+                               (@arg offset: -o --offset +takes_value
+                                "Added to the file's addresses. (default 0)")))
                             )
     ).get_matches();
 
@@ -73,13 +168,44 @@ fn main() {
                 Some(pirate) => {
                     println!("Testing {:?}", pirate);
                     match pirate.open() {
-                        Ok(mut p) => {
+                        Ok(p) => {
                             println!("Yay! Opened {:?} as {:#?}",
                                      pirate.device.to_str(), p);
-                            match p.enter_bio_mode() {
-                                Err(e) => println!("Testing failed: {:#?}", e),
-                                Ok(c) => println!("Good bbio con {:?}!",
-                                                  c.vsn)
+                            let mut c = match p.resync() {
+                                Err(e) => {
+                                    println!("Testing failed: {:#?}", e);
+                                    std::process::exit(1);
+                                },
+                                Ok((c, state)) => {
+                                    println!("Found it in the {:?} state.", state);
+                                    c
+                                }
+                            };
+                            println!("Good bbio con {:?}!", c.vsn);
+                            if c.info.is_none() {
+                                // Found in a binary mode, where the
+                                // version wasn't read.
+                                if let Err(e) = c.read_info() {
+                                    println!("Couldn't read the version: {}", e);
+                                    std::process::exit(1);
+                                }
+                            }
+                            if !c.capabilities().contains(Capabilities::SELF_TEST) {
+                                println!("Self-test not available on this hardware.");
+                                return;
+                            }
+                            match c.self_test(test.is_present("long")) {
+                                Err(e) => {
+                                    println!("Self-test failed to run: {}", e);
+                                    std::process::exit(1);
+                                },
+                                Ok(ref report) if report.passed() =>
+                                    println!("Self-test passed."),
+                                Ok(report) => {
+                                    println!("Self-test FAILED with {} error(s).",
+                                             report.errors);
+                                    std::process::exit(1);
+                                }
                             }
                         },
                         Err(e) => {
@@ -104,35 +230,155 @@ fn main() {
                                           device.device.to_str().unwrap(), s)))
                 .expect("Couldn't get version string.");
         },
+        Some("freq") => {
+            let freq_matches = matches.subcommand_matches("freq").unwrap();
+            let count = value_t!(freq_matches, "count", u64).unwrap_or(1);
+            let interval = value_t!(freq_matches, "interval", u64).unwrap_or(1000);
+            let mut bbio = pirates.find_or_default(freq_matches.value_of("dev"))
+                .expect("Couldn't find a bus pirate device.")
+                .open()
+                .expect("Couldn't open bus_pirate")
+                .enter_bio_mode()
+                .expect("Couldn't enter binary IO mode");
+
+            let mut taken = 0;
+            while count == 0 || taken < count {
+                if taken > 0 {
+                    std::thread::sleep(std::time::Duration::from_millis(interval));
+                }
+                let hz = bbio.measure_frequency()
+                    .expect("Couldn't measure frequency.");
+                println!("{} Hz", hz);
+                taken += 1;
+            }
+        },
         Some("i2c") => {
             let i2c_matches = matches.subcommand_matches("i2c").unwrap();
             let dev = pirates.find_or_default(i2c_matches.value_of("dev"));
-            let voltage = value_t!(i2c_matches, "voltage", PullUp);
+            let voltage = if i2c_matches.is_present("voltage") {
+                Some(value_t!(i2c_matches, "voltage", PullUp)
+                     .unwrap_or_else(|e| e.exit()))
+            } else {
+                None
+            };
             let speed = value_t!(i2c_matches, "speed", Speed)
                 .unwrap_or(Speed::Hz100000);
-            let dryrun: bool = value_t!(i2c_matches, "dryrun", bool)
-                .unwrap_or(true);
-            println!("I2C: dev: {:?} voltage: {:?} speed: {:?} dryrun: {:?}",
-                     dev, voltage, speed, dryrun);
+            let power = i2c_matches.is_present("power");
+            let dryrun = i2c_matches.is_present("dryrun");
+            println!("I2C: dev: {:?} voltage: {:?} speed: {:?} power: {:?} dryrun: {:?}",
+                     dev, voltage, speed, power, dryrun);
 
             match i2c_matches.subcommand_name() {
-                Some("scan") => {},
+                Some("scan") => {
+                    let scan = i2c_matches.subcommand_matches("scan").unwrap();
+                    let skip = if scan.is_present("all") { &[][..] } else { &RESERVED_ADDRS[..] };
+                    let mut i2c = enter_i2c_mode(dev.expect("Couldn't find a bus_pirate"));
+
+                    i2c.configure(&BusSettings::new(speed, voltage, power, false, false))
+                        .expect("Couldn't configure the I2C bus");
+                    let found = i2c.scan_skipping(skip)
+                        .expect("Couldn't scan the I2C bus");
+
+                    // Laid out like i2cdetect: skipped addresses are
+                    // blank, silent ones "--".
+                    println!("     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
+                    for row in 0..8u8 {
+                        print!("{:02x}:", row << 4);
+                        for addr in (row << 4)..((row << 4) + 16) {
+                            if skip.iter().any(|range| range.contains(&addr)) {
+                                print!("   ");
+                            } else if found.iter().any(|f| f.addr == addr) {
+                                print!(" {:02x}", addr);
+                            } else {
+                                print!(" --");
+                            }
+                        }
+                        println!();
+                    }
+                    for f in found.iter().filter(|f| !(f.read && f.write)) {
+                        println!("{:#04x} only ACKs {}.", f.addr,
+                                 if f.write { "writes" } else { "reads" });
+                    }
+                },
                 Some("test") => {
-                    let mut i2c = dev.expect("Couldn't find a bus_pirate")
-                        .open()
-                        .expect("Couldn't open bus_pirate")
-                        .enter_bio_mode()
-                        .expect("Couldn't enter binary IO mode")
-                        .enter_i2c_mode()
-                        .expect("Couldn't enter binary I2C mode");
-
-                    i2c.configure(&BusSettings::new(speed, !dryrun, false, false))
+                    let mut i2c = enter_i2c_mode(dev.expect("Couldn't find a bus_pirate"));
+
+                    i2c.configure(&BusSettings::new(speed, voltage, power, false, false))
                         .expect("Couldn't configure the I2C bus");
                     println!("Configured! Yay!");
                     i2c.test()
                         .expect("Failed to get I2C vsn.");
                     println!("I guess that worked! Yay!");
                 }
+                Some("eeprom") => {
+                    let eeprom_matches = i2c_matches.subcommand_matches("eeprom").unwrap();
+                    let part = value_t!(eeprom_matches, "part", Part)
+                        .unwrap_or_else(|e| e.exit());
+                    let pins = if eeprom_matches.is_present("pins") {
+                        value_t!(eeprom_matches, "pins", u8).unwrap_or_else(|e| e.exit())
+                    } else {
+                        0
+                    };
+                    let mut i2c = enter_i2c_mode(dev.expect("Couldn't find a bus_pirate"));
+
+                    i2c.configure(&BusSettings::new(speed, voltage, power, false, false))
+                        .expect("Couldn't configure the I2C bus");
+                    let mut eeprom = Eeprom::new(&mut i2c, part, pins);
+                    let size = eeprom.geometry().size;
+
+                    match eeprom_matches.subcommand() {
+                        ("read", Some(args)) => {
+                            let offset = parse_num(args.value_of("offset"), 0);
+                            let len = parse_num(args.value_of("len"),
+                                                size.saturating_sub(offset));
+                            let data = eeprom.read(offset, len)
+                                .expect("Couldn't read the EEPROM");
+                            save_image(args.value_of("file").unwrap(), offset, &data)
+                                .expect("Couldn't write the file");
+                            println!("Read {} bytes from {:#x}.", len, offset);
+                        },
+                        ("write", Some(args)) => {
+                            let offset = parse_num(args.value_of("offset"), 0);
+                            let image = load_image(args.value_of("file").unwrap(), offset)
+                                .expect("Couldn't read the file");
+                            if dryrun {
+                                for &(at, ref data) in &image {
+                                    println!("Would write {} bytes at {:#x}.", data.len(), at);
+                                }
+                                return;
+                            }
+                            for &(at, ref data) in &image {
+                                eeprom.write(at, data)
+                                    .expect("Couldn't write the EEPROM");
+                                println!("Wrote {} bytes at {:#x}.", data.len(), at);
+                            }
+                            if !args.is_present("noverify") {
+                                for &(at, ref data) in &image {
+                                    eeprom.verify(at, data)
+                                        .unwrap_or_else(|e| {
+                                            println!("{}", e);
+                                            std::process::exit(1);
+                                        });
+                                }
+                                println!("Verified.");
+                            }
+                        },
+                        ("verify", Some(args)) => {
+                            let offset = parse_num(args.value_of("offset"), 0);
+                            let image = load_image(args.value_of("file").unwrap(), offset)
+                                .expect("Couldn't read the file");
+                            for &(at, ref data) in &image {
+                                eeprom.verify(at, data)
+                                    .unwrap_or_else(|e| {
+                                        println!("{}", e);
+                                        std::process::exit(1);
+                                    });
+                            }
+                            println!("Verified.");
+                        },
+                        _ => unreachable!()
+                    }
+                }
                 Some(ref c) => {
                     println!("Unknown i2c command: {}", c);
                     std::process::exit(1);
@@ -142,6 +388,97 @@ fn main() {
                 }
             }
         },
+        Some("spi") => {
+            let spi_matches = matches.subcommand_matches("spi").unwrap();
+            let speed = value_t!(spi_matches, "speed", spi::Speed)
+                .unwrap_or(spi::Speed::Hz1000000);
+            let mut spi = pirates.find_or_default(spi_matches.value_of("dev"))
+                .expect("Couldn't find a bus_pirate")
+                .open()
+                .expect("Couldn't open bus_pirate")
+                .enter_bio_mode()
+                .expect("Couldn't enter binary IO mode")
+                .enter_spi_mode()
+                .expect("Couldn't enter binary SPI mode");
+            let config = spi::Config { output: spi::Output::V3_3, ..Default::default() };
+            spi.configure(&spi::BusSettings::new(speed, config,
+                                                 spi_matches.is_present("power"),
+                                                 false, false, true))
+                .expect("Couldn't configure the SPI bus");
+
+            let flash_matches = spi_matches.subcommand_matches("flash").unwrap();
+            let mut flash = Flash::new(&mut spi).expect("Couldn't identify the flash");
+            let size = flash.geometry().size;
+            println!("Flash {}: {} bytes, {} byte pages, erase sizes {:?}",
+                     flash.id(), size, flash.geometry().page_size,
+                     flash.geometry().erase.iter().map(|e| e.size).collect::<Vec<_>>());
+
+            if let Some("write") | Some("erase") = flash_matches.subcommand_name() {
+                if flash_matches.is_present("unprotect") {
+                    flash.unprotect().expect("Couldn't unprotect the flash");
+                } else {
+                    let status = flash.status().expect("Couldn't read the status register");
+                    if status.intersects(Status::PROTECT) {
+                        println!("Flash is write protected (status {:#04x}), use -u to clear it.",
+                                 status.bits());
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            match flash_matches.subcommand() {
+                ("read", Some(args)) => {
+                    let offset = parse_num(args.value_of("offset"), 0);
+                    let len = parse_num(args.value_of("len"), size.saturating_sub(offset));
+                    let data = flash.read(offset, len)
+                        .expect("Couldn't read the flash");
+                    save_image(args.value_of("file").unwrap(), offset, &data)
+                        .expect("Couldn't write the file");
+                    println!("Read {} bytes from {:#x}.", len, offset);
+                },
+                ("write", Some(args)) => {
+                    let offset = parse_num(args.value_of("offset"), 0);
+                    let image = load_image(args.value_of("file").unwrap(), offset)
+                        .expect("Couldn't read the file");
+                    for &(at, ref data) in &image {
+                        flash.write(at, data)
+                            .expect("Couldn't write the flash");
+                        println!("Wrote {} bytes at {:#x}.", data.len(), at);
+                    }
+                    if !args.is_present("noverify") {
+                        for &(at, ref data) in &image {
+                            flash.verify(at, data)
+                                .unwrap_or_else(|e| {
+                                    println!("{}", e);
+                                    std::process::exit(1);
+                                });
+                        }
+                        println!("Verified.");
+                    }
+                },
+                ("erase", Some(args)) => {
+                    let offset = parse_num(args.value_of("offset"), 0);
+                    let len = parse_num(args.value_of("len"), size.saturating_sub(offset));
+                    flash.erase(offset, len)
+                        .expect("Couldn't erase the flash");
+                    println!("Erased {} bytes from {:#x}.", len, offset);
+                },
+                ("verify", Some(args)) => {
+                    let offset = parse_num(args.value_of("offset"), 0);
+                    let image = load_image(args.value_of("file").unwrap(), offset)
+                        .expect("Couldn't read the file");
+                    for &(at, ref data) in &image {
+                        flash.verify(at, data)
+                            .unwrap_or_else(|e| {
+                                println!("{}", e);
+                                std::process::exit(1);
+                            });
+                    }
+                    println!("Verified.");
+                },
+                _ => unreachable!()
+            }
+        },
         _ => {
             println!("Unknown subcommand.");
             std::process::exit(1);
@@ -150,3 +487,94 @@ fn main() {
 
     std::process::exit(0);
 }
+
+// Open binary I2C mode with the version read: it gates the pull-up
+// voltage and the faster speeds, and isn't read on the way in when the
+// Pirate was left in a binary mode.
+fn enter_i2c_mode(dev: &Device) -> I2CConn {
+    let mut bbio = dev.open()
+        .expect("Couldn't open bus_pirate")
+        .enter_bio_mode()
+        .expect("Couldn't enter binary IO mode");
+    if bbio.info.is_none() {
+        bbio.read_info().expect("Couldn't read the bus pirate version");
+    }
+    bbio.enter_i2c_mode().expect("Couldn't enter binary I2C mode")
+}
+
+// Parse a decimal or 0x prefixed hex number, exiting on nonsense.
+fn parse_num(arg: Option<&str>, default: usize) -> usize {
+    match arg {
+        None => default,
+        Some(s) => {
+            let parsed = match s.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => s.parse()
+            };
+            parsed.unwrap_or_else(|_| {
+                println!("Invalid number: {}", s);
+                std::process::exit(1);
+            })
+        }
+    }
+}
+
+fn is_hex(path: &str) -> bool {
+    path.ends_with(".hex") || path.ends_with(".ihex")
+}
+
+// Read a file as runs of (address, bytes). Intel HEX files carry
+// their own addresses, binary files are one run; `offset` is added
+// either way.
+fn load_image(path: &str, offset: usize) -> io::Result<Vec<(usize, Vec<u8>)>> {
+    if !is_hex(path) {
+        return Ok(vec![(offset, fs::read(path)?)]);
+    }
+    let text = fs::read_to_string(path)?;
+    let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+    let mut base = 0;
+    for record in ihex::Reader::new(&text) {
+        match record.map_err(io::Error::other)? {
+            ihex::Record::Data { offset: at, value } => {
+                let at = offset + base + at as usize;
+                match runs.last_mut() {
+                    Some(&mut (start, ref mut data)) if start + data.len() == at =>
+                        data.extend(value),
+                    _ => runs.push((at, value))
+                }
+            },
+            ihex::Record::ExtendedLinearAddress(upper) => base = (upper as usize) << 16,
+            ihex::Record::ExtendedSegmentAddress(segment) => base = (segment as usize) << 4,
+            ihex::Record::EndOfFile => break,
+            _ => ()
+        }
+    }
+    Ok(runs)
+}
+
+// Write `data`, read from `offset`, as Intel HEX at its real
+// addresses or as a plain binary file.
+fn save_image(path: &str, offset: usize, data: &[u8]) -> io::Result<()> {
+    if !is_hex(path) {
+        return fs::write(path, data);
+    }
+    let mut records = Vec::new();
+    let mut upper = 0;
+    let mut done = 0;
+    while done < data.len() {
+        let at = offset + done;
+        if at >> 16 != upper {
+            upper = at >> 16;
+            records.push(ihex::Record::ExtendedLinearAddress(upper as u16));
+        }
+        // A record's 16-bit offset can't carry past the 64K segment.
+        let len = min(16, min(data.len() - done, 0x10000 - (at & 0xffff)));
+        records.push(ihex::Record::Data { offset: (at & 0xffff) as u16,
+                                          value: data[done..done + len].to_vec() });
+        done += len;
+    }
+    records.push(ihex::Record::EndOfFile);
+    let text = ihex::create_object_file_representation(&records)
+        .map_err(io::Error::other)?;
+    fs::write(path, text)
+}
@@ -0,0 +1,219 @@
+use serial::SystemPort;
+use std::str::FromStr;
+use std::result::Result;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+
+use super::i2c::{Addr, I2CConn};
+use super::transport::Transport;
+
+/// The 24Cxx serial EEPROMs, 1Kbit to 1Mbit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Part {
+    C01,
+    C02,
+    C04,
+    C08,
+    C16,
+    C32,
+    C64,
+    C128,
+    C256,
+    C512,
+    C1024
+}
+
+/// How a part is laid out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Geometry {
+    /// Size in bytes.
+    pub size: usize,
+    /// Address bytes sent after the device address.
+    pub addr_bytes: usize,
+    /// Bytes one write cycle can program, writes wrap at the page end.
+    pub page_size: usize
+}
+
+impl Geometry {
+    // Bytes the address bytes reach, the rest is chosen with block
+    // select bits in the device address.
+    fn block_size(&self) -> usize {
+        1 << (8 * self.addr_bytes)
+    }
+
+    /// How many low bits of the device address select the block
+    /// instead of following the A0-A2 pins.
+    pub fn block_bits(&self) -> u32 {
+        (self.size / self.block_size()).max(1).trailing_zeros()
+    }
+}
+
+impl Part {
+    pub fn geometry(&self) -> Geometry {
+        let (size, addr_bytes, page_size) = match *self {
+            Part::C01 => (128, 1, 8),
+            Part::C02 => (256, 1, 8),
+            Part::C04 => (512, 1, 16),
+            Part::C08 => (1024, 1, 16),
+            Part::C16 => (2048, 1, 16),
+            Part::C32 => (4096, 2, 32),
+            Part::C64 => (8192, 2, 32),
+            Part::C128 => (16384, 2, 64),
+            Part::C256 => (32768, 2, 64),
+            Part::C512 => (65536, 2, 128),
+            Part::C1024 => (131072, 2, 256)
+        };
+        Geometry { size: size, addr_bytes: addr_bytes, page_size: page_size }
+    }
+}
+
+impl FromStr for Part {
+    type Err = &'static str;
+
+    /// Takes the usual part names: "24c02", "24LC256", "AT24C1024", "c16"
+    /// or just the size in Kbit.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        let s = s.trim_start_matches(|c: char| c.is_alphabetic());
+        let s = s.strip_prefix("24").unwrap_or(s);
+        match s.trim_start_matches(|c: char| c.is_alphabetic()) {
+            "01" | "1" => Ok(Part::C01),
+            "02" | "2" => Ok(Part::C02),
+            "04" | "4" => Ok(Part::C04),
+            "08" | "8" => Ok(Part::C08),
+            "16" => Ok(Part::C16),
+            "32" => Ok(Part::C32),
+            "64" => Ok(Part::C64),
+            "128" => Ok(Part::C128),
+            "256" => Ok(Part::C256),
+            "512" => Ok(Part::C512),
+            "1024" => Ok(Part::C1024),
+            _ => Err("Unknown EEPROM part")
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum EepromError {
+    #[fail(display="{} bytes at {:#x} don't fit in a {} byte EEPROM",
+           len, offset, size)]
+    OutOfRange { offset: usize, len: usize, size: usize },
+    #[fail(display="write cycle at {:#x} didn't finish", offset)]
+    WriteTimeout { offset: usize },
+    #[fail(display="verify failed at {:#x}: expected {:#04x}, read {:#04x}",
+           offset, expected, found)]
+    Mismatch { offset: usize, expected: u8, found: u8 }
+}
+
+/// The base address of a 24Cxx with its A0-A2 pins tied low.
+pub const BASE_ADDR: Addr = 0x50;
+
+// Parts finish a write cycle within 5-10ms, give them plenty.
+const WRITE_CYCLE_TIMEOUT: Duration = Duration::from_millis(50);
+// The most one write then read can fetch.
+const MAX_READ: usize = 4096;
+
+pub struct Eeprom<'a, T: Transport + 'a = SystemPort> {
+    conn: &'a mut I2CConn<T>,
+    geometry: Geometry,
+    addr: Addr
+}
+
+impl<'a, T: Transport> Eeprom<'a, T> {
+    /// `pins` is the A2-A1-A0 strapping (0-7). Pins whose address bits
+    /// the part uses for block select are ignored.
+    pub fn new(conn: &'a mut I2CConn<T>, part: Part, pins: u8) -> Self {
+        let geometry = part.geometry();
+        let block_mask = (1 << geometry.block_bits()) - 1;
+        Self { conn: conn,
+               geometry: geometry,
+               addr: BASE_ADDR | (pins & 0b111 & !block_mask) }
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), Error> {
+        if offset + len > self.geometry.size {
+            return Err(EepromError::OutOfRange { offset: offset,
+                                                 len: len,
+                                                 size: self.geometry.size }.into());
+        }
+        Ok(())
+    }
+
+    // The device address and memory address bytes that reach `offset`.
+    fn address(&self, offset: usize) -> (Addr, Vec<u8>) {
+        let block = offset / self.geometry.block_size();
+        let bytes = (0..self.geometry.addr_bytes).rev()
+            .map(|i| (offset >> (8 * i)) as u8)
+            .collect();
+        (self.addr | block as Addr, bytes)
+    }
+
+    /// Sequential read of `len` bytes from `offset`.
+    pub fn read(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        self.check_range(offset, len)?;
+        let block_size = self.geometry.block_size();
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let at = offset + data.len();
+            // Sequential reads wrap inside a block, so stop at its end.
+            let n = (len - data.len())
+                .min(block_size - at % block_size)
+                .min(MAX_READ);
+            let (addr, pointer) = self.address(at);
+            data.extend(self.conn.write_then_read(addr, &pointer, n)?);
+        }
+        Ok(data)
+    }
+
+    /// Write `data` from `offset` a page at a time, waiting out each
+    /// write cycle.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.check_range(offset, data.len())?;
+        let page_size = self.geometry.page_size;
+        let mut done = 0;
+        while done < data.len() {
+            let at = offset + done;
+            let n = (data.len() - done).min(page_size - at % page_size);
+            let (addr, mut bytes) = self.address(at);
+            bytes.extend(&data[done..done + n]);
+            self.conn.write(addr, &bytes)?;
+            self.wait_for_write(addr, at)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    // The part ignores its address until the write cycle is over, so
+    // poll it until it ACKs.
+    fn wait_for_write(&mut self, addr: Addr, offset: usize) -> Result<(), Error> {
+        let start = Instant::now();
+        loop {
+            self.conn.start()?;
+            let acked = self.conn.bulk_write(&[addr << 1])?[0];
+            self.conn.stop()?;
+            if acked {
+                return Ok(());
+            }
+            if start.elapsed() > WRITE_CYCLE_TIMEOUT {
+                return Err(EepromError::WriteTimeout { offset: offset }.into());
+            }
+        }
+    }
+
+    /// Read back from `offset` and compare with `data`, failing at the
+    /// first difference.
+    pub fn verify(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let found = self.read(offset, data.len())?;
+        match data.iter().zip(&found).position(|(a, b)| a != b) {
+            Some(i) => Err(EepromError::Mismatch { offset: offset + i,
+                                                   expected: data[i],
+                                                   found: found[i] }.into()),
+            None => Ok(())
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! embedded-hal trait implementations, so platform-agnostic driver
+//! crates can run on the host through a Pirate.
+
+use serial::SystemPort;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use embedded_hal::digital::{self, InputPin, OutputPin, StatefulOutputPin};
+use embedded_hal::i2c::{self, I2c, NoAcknowledgeSource, Operation};
+use embedded_hal::spi::{self, SpiBus, SpiDevice};
+
+use failure;
+
+use super::bbio::{BBIOConn, Pin, Pins};
+use super::i2c::{Addr, I2CConn, I2CError};
+use super::spi::SpiConn;
+use super::transport::Transport;
+
+/// The error every embedded-hal implementation here returns: the
+/// underlying failure, with its embedded-hal kind worked out from it.
+#[derive(Debug)]
+pub struct Error(pub failure::Error);
+
+impl From<failure::Error> for Error {
+    fn from(e: failure::Error) -> Self {
+        Error(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl i2c::Error for Error {
+    fn kind(&self) -> i2c::ErrorKind {
+        match self.0.downcast_ref::<I2CError>() {
+            Some(&I2CError::AddressNack { .. }) =>
+                i2c::ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+            Some(&I2CError::DataNack { .. }) =>
+                i2c::ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+            Some(&I2CError::WriteThenReadFailed { .. }) =>
+                i2c::ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            _ => i2c::ErrorKind::Other
+        }
+    }
+}
+
+impl spi::Error for Error {
+    fn kind(&self) -> spi::ErrorKind {
+        spi::ErrorKind::Other
+    }
+}
+
+impl digital::Error for Error {
+    fn kind(&self) -> digital::ErrorKind {
+        digital::ErrorKind::Other
+    }
+}
+
+// The most write then read can send, including the address byte.
+const MAX_BUFFERED: usize = 4096;
+
+impl<T: Transport> i2c::ErrorType for I2CConn<T> {
+    type Error = Error;
+}
+
+/// Plain reads and writes go through the buffered write then read
+/// command. `write_read` and `transaction` need repeated starts, so
+/// they're built from the start/bulk write/read primitives and take a
+/// round trip per byte read.
+impl<T: Transport> I2c for I2CConn<T> {
+    fn read(&mut self, address: Addr, read: &mut [u8]) -> Result<(), Error> {
+        if read.is_empty() || read.len() > MAX_BUFFERED {
+            return self.transaction(address, &mut [Operation::Read(read)]);
+        }
+        let data = I2CConn::write_then_read(self, address, &[], read.len())?;
+        read.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write(&mut self, address: Addr, write: &[u8]) -> Result<(), Error> {
+        if write.len() < MAX_BUFFERED {
+            I2CConn::write_then_read(self, address, write, 0)?;
+        } else {
+            I2CConn::write(self, address, write)?;
+        }
+        Ok(())
+    }
+
+    fn write_read(&mut self, address: Addr, write: &[u8], read: &mut [u8])
+                  -> Result<(), Error> {
+        self.transaction(address, &mut [Operation::Write(write), Operation::Read(read)])
+    }
+
+    fn transaction(&mut self, address: Addr, operations: &mut [Operation<'_>])
+                   -> Result<(), Error> {
+        let res = run_transaction(self, address, operations);
+        // Release the bus whatever happened.
+        let stop = self.stop();
+        res?;
+        stop?;
+        Ok(())
+    }
+}
+
+// Adjacent operations in the same direction share one (repeated)
+// start, and a read NACKs its last byte only when no read follows.
+fn run_transaction<T: Transport>(conn: &mut I2CConn<T>, addr: Addr,
+                                 operations: &mut [Operation<'_>])
+                                 -> Result<(), failure::Error> {
+    let mut reading = None;
+    let count = operations.len();
+    for i in 0..count {
+        let read = match operations[i] {
+            Operation::Read(_) => true,
+            Operation::Write(_) => false
+        };
+        let read_next = matches!(operations.get(i + 1), Some(&Operation::Read(_)));
+        let mut offset = 0;
+        if reading != Some(read) {
+            conn.start()?;
+            if !conn.bulk_write(&[addr << 1 | read as u8])?[0] {
+                return Err(I2CError::AddressNack { addr: addr }.into());
+            }
+            reading = Some(read);
+        }
+        match operations[i] {
+            Operation::Write(bytes) => {
+                for chunk in bytes.chunks(16) {
+                    let acks = conn.bulk_write(chunk)?;
+                    if let Some(nacked) = acks.iter().position(|&ack| !ack) {
+                        return Err(I2CError::DataNack { addr: addr,
+                                                        offset: offset + nacked }.into());
+                    }
+                    offset += chunk.len();
+                }
+            }
+            Operation::Read(ref mut buf) => {
+                let len = buf.len();
+                for (n, byte) in buf.iter_mut().enumerate() {
+                    *byte = conn.read_byte()?;
+                    if n + 1 == len && !read_next {
+                        conn.nack()?;
+                    } else {
+                        conn.ack()?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<T: Transport> spi::ErrorType for SpiConn<T> {
+    type Error = Error;
+}
+
+/// The bus without CS, which stays wherever `cs_low`/`cs_high` left it.
+/// Writes and reads use the buffered write then read command, 4096
+/// bytes at a time; full duplex transfers go 16 bytes at a time.
+impl<T: Transport> SpiBus for SpiConn<T> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        for chunk in words.chunks_mut(MAX_BUFFERED) {
+            let data = self.write_then_read_no_cs(&[], chunk.len())?;
+            chunk.copy_from_slice(&data);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        for chunk in words.chunks(MAX_BUFFERED) {
+            self.write_then_read_no_cs(chunk, 0)?;
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Error> {
+        // Pad the shorter side: extra writes send 0x00, extra reads
+        // are dropped.
+        let mut out = write.to_vec();
+        out.resize(read.len().max(write.len()), 0x00);
+        let data = SpiConn::transfer(self, &out)?;
+        let len = read.len();
+        read.copy_from_slice(&data[..len]);
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        let data = SpiConn::transfer(self, words)?;
+        words.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The Pirate's CS as the one device on the bus. A lone write followed
+/// by a read goes out as a single write then read command.
+impl<T: Transport> SpiDevice for SpiConn<T> {
+    fn transaction(&mut self, operations: &mut [spi::Operation<'_, u8>])
+                   -> Result<(), Error> {
+        if let [spi::Operation::Write(write), spi::Operation::Read(ref mut read)] = *operations {
+            if write.len() <= MAX_BUFFERED && read.len() <= MAX_BUFFERED {
+                let data = self.write_then_read(write, read.len())?;
+                read.copy_from_slice(&data);
+                return Ok(());
+            }
+        }
+        self.cs_low()?;
+        let res = run_spi_operations(self, operations);
+        // Release CS whatever happened.
+        let cs = self.cs_high();
+        res?;
+        cs?;
+        Ok(())
+    }
+}
+
+fn run_spi_operations<T: Transport>(conn: &mut SpiConn<T>,
+                                    operations: &mut [spi::Operation<'_, u8>])
+                                    -> Result<(), Error> {
+    for op in operations {
+        match *op {
+            spi::Operation::Read(ref mut words) => SpiBus::read(conn, words)?,
+            spi::Operation::Write(words) => SpiBus::write(conn, words)?,
+            spi::Operation::Transfer(ref mut read, write) =>
+                SpiBus::transfer(conn, read, write)?,
+            spi::Operation::TransferInPlace(ref mut words) =>
+                SpiBus::transfer_in_place(conn, words)?,
+            spi::Operation::DelayNs(ns) => thread::sleep(Duration::from_nanos(ns as u64))
+        }
+    }
+    Ok(())
+}
+
+/// One of the five IO pins in bitbang mode. The pins share the
+/// connection, so a driver can be handed several of them at once.
+pub struct BitbangPin<T: Transport = SystemPort> {
+    conn: Rc<RefCell<BBIOConn<T>>>,
+    pin: Pins
+}
+
+impl<T: Transport> BitbangPin<T> {
+    /// Make `pin` an output, starting low.
+    pub fn output(conn: Rc<RefCell<BBIOConn<T>>>, pin: Pin) -> Result<Self, Error> {
+        let pin = Pins::from(pin);
+        {
+            let mut bbio = conn.borrow_mut();
+            bbio.set_pin(pin, false).map_err(failure::Error::from)?;
+            bbio.set_direction(pin, false).map_err(failure::Error::from)?;
+        }
+        Ok(Self { conn: conn, pin: pin })
+    }
+
+    /// Make `pin` an input.
+    pub fn input(conn: Rc<RefCell<BBIOConn<T>>>, pin: Pin) -> Result<Self, Error> {
+        let pin = Pins::from(pin);
+        conn.borrow_mut().set_direction(pin, true).map_err(failure::Error::from)?;
+        Ok(Self { conn: conn, pin: pin })
+    }
+
+    fn read(&mut self) -> Result<bool, Error> {
+        let state = self.conn.borrow_mut().read_pins().map_err(failure::Error::from)?;
+        Ok(state.contains(self.pin))
+    }
+}
+
+impl<T: Transport> digital::ErrorType for BitbangPin<T> {
+    type Error = Error;
+}
+
+impl<T: Transport> OutputPin for BitbangPin<T> {
+    fn set_low(&mut self) -> Result<(), Error> {
+        self.conn.borrow_mut().set_pin(self.pin, false).map_err(failure::Error::from)?;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Error> {
+        self.conn.borrow_mut().set_pin(self.pin, true).map_err(failure::Error::from)?;
+        Ok(())
+    }
+}
+
+impl<T: Transport> StatefulOutputPin for BitbangPin<T> {
+    fn is_set_high(&mut self) -> Result<bool, Error> {
+        Ok(self.conn.borrow().outputs().contains(self.pin))
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Error> {
+        Ok(!self.conn.borrow().outputs().contains(self.pin))
+    }
+}
+
+impl<T: Transport> InputPin for BitbangPin<T> {
+    fn is_high(&mut self) -> Result<bool, Error> {
+        self.read()
+    }
+
+    fn is_low(&mut self) -> Result<bool, Error> {
+        Ok(!self.read()?)
+    }
+}
@@ -1,13 +1,18 @@
 use serial::SystemPort;
 use std::str::FromStr;
 use std::result::Result;
-use std::io::{Read, Write};
-use std::iter;
+use std::io::{self, ErrorKind};
+use std::ops::RangeInclusive;
 
 use failure::Error;
 
-pub struct I2CConn {
-    port: SystemPort,
+use super::info::{self, Capabilities, PirateInfo};
+use super::protocol::{self, Command, InvalidReply};
+use super::transport::Transport;
+
+pub struct I2CConn<T: Transport = SystemPort> {
+    port: T,
+    info: Option<PirateInfo>
 }
 
 pub type Addr = u8;
@@ -21,25 +26,75 @@ pub struct BusSettings {
 }
 
 impl BusSettings {
-    pub fn new(speed: Speed, power: bool, aux: bool, cs: bool) -> Self {
+    /// With `voltage` set the pull-ups are switched on and connected
+    /// to that supply, which only a v4 can do. None, or
+    /// `Some(PullUp::None)`, leaves them off.
+    pub fn new(speed: Speed, voltage: Option<PullUp>,
+               power: bool, aux: bool, cs: bool) -> Self {
         Self { speed: speed,
-               voltage: None,
+               voltage: voltage,
                power: power,
                aux: aux,
                cs: cs }
     }
 }
 
+/// Errors from I2C calls, recoverable from a `failure::Error` with
+/// `downcast_ref::<I2CError>()`.
 #[derive(Debug, Fail)]
-enum CallError {
-    #[fail(display="sent: {:?} expected {:?}, received {:?}",
-           sent, expected, received)]
-    InvalidReply { sent: Message, expected: Vec<u8>, received: Vec<u8> }
+pub enum I2CError {
+    #[fail(display="can't send {} bytes in one {} command (max {})",
+           len, command, max)]
+    InvalidLength { command: &'static str, len: usize, max: usize },
+    /// The Pirate answered a write then read with 0x00: a byte was
+    /// NACKed or the lengths were out of bounds.
+    #[fail(display="write then read of {:#04x} failed (NACK or bad length)", addr)]
+    WriteThenReadFailed { addr: Addr },
+    #[fail(display="{:#04x} isn't a 7-bit I2C address", addr)]
+    InvalidAddress { addr: Addr },
+    #[fail(display="no device ACKed address {:#04x}", addr)]
+    AddressNack { addr: Addr },
+    /// `offset` is the index into the data being written.
+    #[fail(display="{:#04x} NACKed byte {} of the write", addr, offset)]
+    DataNack { addr: Addr, offset: usize },
+    /// The Pirate answered the pull-up select with 0x00 and left both
+    /// rails disconnected.
+    #[fail(display="voltage present on VEXTERN, pull-up supply not connected")]
+    ExternalVoltage
+}
+
+/// The address blocks the I2C spec reserves: general call, START
+/// byte, CBUS, other bus formats and HS-mode masters (0x00-0x07), and
+/// 10-bit addressing and device ID (0x78-0x7F). `scan` skips them.
+pub const RESERVED_ADDRS: [RangeInclusive<Addr>; 2] = [0x00..=0x07, 0x78..=0x7F];
+
+/// A device that answered a scan.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ScanResult {
+    pub addr: Addr,
+    /// ACKed its write address.
+    pub write: bool,
+    /// ACKed its read address.
+    pub read: bool
 }
 
-impl I2CConn {
-    pub fn new(port: SystemPort) -> Self {
-        Self { port: port }
+// The largest write or read in one write then read command.
+const MAX_WRITE_THEN_READ: usize = 4096;
+
+impl<T: Transport> I2CConn<T> {
+    pub fn new(port: T) -> Self {
+        Self::with_info(port, None)
+    }
+
+    /// A connection that knows which Pirate it's talking to, which
+    /// gates the pull-up voltage select and the speeds on offer. Without
+    /// it those are refused.
+    pub fn with_info(port: T, info: Option<PirateInfo>) -> Self {
+        Self { port: port, info: info }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        info::capabilities(self.info.as_ref())
     }
 
     pub fn test(&mut self) -> Result<(), Error> {
@@ -48,33 +103,406 @@ impl I2CConn {
     }
 
     fn call(&mut self, msg: &Message) -> Result<Vec<u8>, Error> {
-        try!(self.port.write_all(&msg.send()));
-        let good_reply = msg.expect().expect("Couldn't encode message");
-        let mut reply = iter::repeat::<u8>(0)
-            .take(good_reply.len()).collect::<Vec<u8>>();
-
-        try!(self.port.read_exact(&mut reply));
-        if reply.eq(&good_reply) {
-            Ok(reply)
-        } else {
-            Err(CallError::InvalidReply{ sent: msg.clone(),
-                                         expected: good_reply,
-                                         received: reply })?
-        }
+        protocol::call(&mut self.port, msg)
     }
 
+    /// A pull-up voltage needs the v4's voltage switch, other hardware
+    /// refuses it with an `Unsupported` error before anything is
+    /// switched on.
     pub fn configure(&mut self, settings: &BusSettings) -> Result<(), Error> {
-        self.call(&Message::SetSpeed(settings.speed))?;
-        self.call(&Message::Configure(settings.power,
-                                      settings.voltage.is_some(),
-                                      settings.aux,
-                                      settings.cs))?;
+        // Pull-ups on with no rail behind them would just float.
+        let voltage = match settings.voltage {
+            Some(PullUp::None) => None,
+            voltage => voltage
+        };
+        self.set_speed(settings.speed)?;
+        if let Some(voltage) = voltage {
+            self.select_pullup(voltage)?;
+        }
+        self.set_peripherals(settings.power, voltage.is_some(),
+                             settings.aux, settings.cs)
+    }
+
+    pub fn set_peripherals(&mut self, power: bool, pullups: bool,
+                           aux: bool, cs: bool) -> Result<(), Error> {
+        self.call(&Message::Configure(power, pullups, aux, cs))?;
+        Ok(())
+    }
+
+    /// Firmware before 4.2 only has 5kHz and 50kHz, which use the same
+    /// bits as on later firmware. The faster speeds are refused there
+    /// with an `Unsupported` error.
+    pub fn set_speed(&mut self, speed: Speed) -> Result<(), Error> {
+        match speed {
+            Speed::Hz400000 | Speed::Hz100000 =>
+                info::require(self.info.as_ref(), Capabilities::I2C_FAST_SPEEDS,
+                              "100kHz and 400kHz I2C")?,
+            Speed::Hz50000 | Speed::Hz5000 => ()
+        }
+        self.call(&Message::SetSpeed(speed))?;
+        Ok(())
+    }
+
+    /// Connect the pull-up supply (VEXTERN) to the 5V or 3.3V rail, or
+    /// neither. Only the v4 has the switch.
+    pub fn select_pullup(&mut self, voltage: PullUp) -> Result<(), Error> {
+        info::require(self.info.as_ref(), Capabilities::PULLUP_SELECT,
+                      "pull-up voltage select")?;
+        self.port.write_all(&Message::PullUpSelect(voltage).send()?)?;
+        let mut reply = [0; 1];
+        self.port.read_exact(&mut reply)?;
+        match reply[0] {
+            0x01 => Ok(()),
+            0x00 => Err(I2CError::ExternalVoltage.into()),
+            _ => Err(InvalidReply { sent: Message::PullUpSelect(voltage),
+                                    expected: vec![0x01],
+                                    received: reply.to_vec() }.into())
+        }
+    }
+
+    /// Send a start (or repeated start) condition.
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.call(&Message::StartBit)?;
+        Ok(())
+    }
+
+    /// Send a stop condition.
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.call(&Message::StopBit)?;
+        Ok(())
+    }
+
+    /// Clock in one byte. The Pirate doesn't acknowledge it for you,
+    /// follow up with `ack` to read more or `nack` before the stop.
+    pub fn read_byte(&mut self) -> Result<u8, Error> {
+        self.port.write_all(&Message::ReadByte.send()?)?;
+        let mut byte = [0; 1];
+        self.port.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    pub fn ack(&mut self) -> Result<(), Error> {
+        self.call(&Message::AckBit)?;
+        Ok(())
+    }
+
+    pub fn nack(&mut self) -> Result<(), Error> {
+        self.call(&Message::NackBit)?;
         Ok(())
     }
 
-impl Drop for I2CConn {
+    /// Write 1-16 bytes in one bulk write, returning true for each byte
+    /// the device ACKed. The Pirate carries on writing after a NACK.
+    pub fn bulk_write(&mut self, bytes: &[u8]) -> Result<Vec<bool>, Error> {
+        if bytes.is_empty() || bytes.len() > 16 {
+            return Err(I2CError::InvalidLength { command: "bulk write",
+                                                 len: bytes.len(),
+                                                 max: 16 }.into());
+        }
+        let msg = Message::BulkWrite(bytes.to_vec());
+        self.port.write_all(&msg.send()?)?;
+        let mut reply = vec![0; bytes.len() + 1];
+        self.port.read_exact(&mut reply)?;
+        if reply[0] != 0x01 {
+            return Err(InvalidReply { sent: msg,
+                                      expected: vec![0x01],
+                                      received: reply }.into());
+        }
+        Ok(reply[1..].iter().map(|&ack| ack == 0x00).collect())
+    }
+
+    /// Write any number of bytes to the device at `addr` in a single
+    /// start/stop transaction, 16 bytes per bulk write. A NACK is
+    /// reported once its bulk write is done: the Pirate has already
+    /// clocked out the rest of that 16 byte chunk, but nothing after
+    /// it is sent.
+    pub fn write(&mut self, addr: Addr, bytes: &[u8]) -> Result<(), Error> {
+        check_addr(addr)?;
+        let mut stream = vec![addr << 1];
+        stream.extend(bytes);
+        self.start()?;
+        if let Err(e) = self.write_stream(addr, &stream) {
+            // The NACK says more than a stop that failed after it.
+            let _ = self.stop();
+            return Err(e);
+        }
+        self.stop()
+    }
+
+    fn write_stream(&mut self, addr: Addr, stream: &[u8]) -> Result<(), Error> {
+        for (n, chunk) in stream.chunks(16).enumerate() {
+            let acks = self.bulk_write(chunk)?;
+            if let Some(i) = acks.iter().position(|&ack| !ack) {
+                return Err(match n * 16 + i {
+                    0 => I2CError::AddressNack { addr: addr },
+                    i => I2CError::DataNack { addr: addr, offset: i - 1 }
+                }.into());
+            }
+        }
+        Ok(())
+    }
+
+    // Address `addr` and report whether it ACKed, releasing the bus
+    // again afterwards.
+    fn probe(&mut self, addr: Addr, read: bool) -> Result<bool, Error> {
+        check_addr(addr)?;
+        self.start()?;
+        let acked = match self.bulk_write(&[addr << 1 | read as u8]) {
+            Ok(acks) => acks[0],
+            Err(e) => {
+                let _ = self.stop();
+                return Err(e);
+            }
+        };
+        if acked && read {
+            // The device owns SDA until it has been NACKed.
+            self.read_byte()?;
+            self.nack()?;
+        }
+        self.stop()?;
+        Ok(acked)
+    }
+
+    /// Probe every 7-bit address outside `RESERVED_ADDRS` for write and
+    /// read ACKs.
+    pub fn scan(&mut self) -> Result<Vec<ScanResult>, Error> {
+        self.scan_skipping(&RESERVED_ADDRS)
+    }
+
+    /// Probe every 7-bit address outside `skip` for write and read
+    /// ACKs, returning the addresses that answered either.
+    pub fn scan_skipping(&mut self, skip: &[RangeInclusive<Addr>])
+                         -> Result<Vec<ScanResult>, Error> {
+        let mut found = Vec::new();
+        for addr in 0..0x80 {
+            if skip.iter().any(|range| range.contains(&addr)) {
+                continue;
+            }
+            let write = self.probe(addr, false)?;
+            let read = self.probe(addr, true)?;
+            if write || read {
+                found.push(ScanResult { addr: addr, write: write, read: read });
+            }
+        }
+        Ok(found)
+    }
+
+    /// Start the bus sniffer. Sniffed traffic is read from the returned
+    /// `Sniffer`, which leaves sniffer mode when dropped.
+    pub fn sniff(&mut self) -> Result<Sniffer<'_, T>, Error> {
+        self.port.write_all(&Message::StartBusSniffer.send()?)?;
+        Ok(Sniffer { conn: self, decoder: Decoder::default() })
+    }
+
+    /// Write `write` to the device at `addr`, then read `read_len`
+    /// bytes back from it, buffered inside the Pirate.
+    ///
+    /// The Pirate can't send a repeated start, so when there is both
+    /// something to write and something to read this takes two
+    /// transactions with a stop in between. That's fine for register
+    /// pointers and EEPROM addresses which survive the stop.
+    pub fn write_then_read(&mut self, addr: Addr, write: &[u8], read_len: usize)
+                           -> Result<Vec<u8>, Error> {
+        check_addr(addr)?;
+        if write.len() + 1 > MAX_WRITE_THEN_READ || read_len > MAX_WRITE_THEN_READ {
+            return Err(I2CError::InvalidLength {
+                command: "write then read",
+                len: (write.len() + 1).max(read_len),
+                max: MAX_WRITE_THEN_READ }.into());
+        }
+        if !write.is_empty() || read_len == 0 {
+            let mut bytes = vec![addr << 1];
+            bytes.extend(write);
+            self.buffered_write_then_read(addr, bytes, 0)?;
+        }
+        if read_len == 0 {
+            return Ok(Vec::new());
+        }
+        self.buffered_write_then_read(addr, vec![addr << 1 | 1], read_len)
+    }
+
+    fn buffered_write_then_read(&mut self, addr: Addr, write: Vec<u8>,
+                                read_len: usize) -> Result<Vec<u8>, Error> {
+        self.port.write_all(&Message::WriteThenRead(write, read_len as u16).send()?)?;
+        let mut status = [0; 1];
+        self.port.read_exact(&mut status)?;
+        if status[0] != 0x01 {
+            return Err(I2CError::WriteThenReadFailed { addr: addr }.into());
+        }
+        let mut reply = vec![0; read_len];
+        self.port.read_exact(&mut reply)?;
+        Ok(reply)
+    }
+}
+
+// Addresses are 7-bit and get shifted up to make room for R/W.
+fn check_addr(addr: Addr) -> Result<(), I2CError> {
+    if addr > 0x7F {
+        return Err(I2CError::InvalidAddress { addr: addr });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SnifferEvent {
+    Start,
+    Stop,
+    Byte(u8),
+    Ack,
+    Nack
+}
+
+pub struct Sniffer<'a, T: Transport + 'a> {
+    conn: &'a mut I2CConn<T>,
+    decoder: Decoder
+}
+
+impl<'a, T: Transport> Sniffer<'a, T> {
+    /// Group the events into transactions instead.
+    pub fn transactions(self) -> Transactions<'a, T> {
+        Transactions { sniffer: self, current: None }
+    }
+}
+
+#[derive(Default)]
+struct Decoder {
+    in_escape: bool
+}
+
+impl Decoder {
+    // Every data byte is escaped with '\', so an unescaped 0x01 can
+    // only be the exit ack.
+    fn decode(&mut self, byte: u8) -> Option<Result<SnifferEvent, u8>> {
+        if self.in_escape {
+            self.in_escape = false;
+            return Some(Ok(SnifferEvent::Byte(byte)));
+        }
+        match byte {
+            b'[' => Some(Ok(SnifferEvent::Start)),
+            b']' => Some(Ok(SnifferEvent::Stop)),
+            b'+' => Some(Ok(SnifferEvent::Ack)),
+            b'-' => Some(Ok(SnifferEvent::Nack)),
+            b'\\' => {
+                self.in_escape = true;
+                None
+            }
+            other => Some(Err(other))
+        }
+    }
+}
+
+/// Yields start, stop, data and ACK/NACK events until nothing
+/// arrives within the port timeout. Iterating again picks up where the
+/// stream left off.
+impl<'a, T: Transport> Iterator for Sniffer<'a, T> {
+    type Item = Result<SnifferEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let byte = match protocol::next_byte(&mut self.conn.port)? {
+                Ok(byte) => byte,
+                Err(e) => return Some(Err(e.into()))
+            };
+            match self.decoder.decode(byte) {
+                None => continue,
+                Some(Ok(event)) => return Some(Ok(event)),
+                Some(Err(other)) =>
+                    return Some(Err(format_err!("unexpected byte {:#04x} from I2C sniffer",
+                                                other)))
+            }
+        }
+    }
+}
+
+impl<'a, T: Transport> Drop for Sniffer<'a, T> {
     fn drop(&mut self) {
-        let _ = self.call(&Message::Configure(false,false,false,false));
+        // Any byte stops the sniffer.
+        let decoder = &mut self.decoder;
+        protocol::exit_stream(&mut self.conn.port, Message::ExitBusSniffer.send(),
+                              |byte| decoder.decode(byte) == Some(Err(0x01)));
+    }
+}
+
+/// One sniffed transfer, from a start to the next stop or repeated
+/// start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub addr: Addr,
+    pub read: bool,
+    /// Whether anything ACKed the address.
+    pub addr_ack: bool,
+    pub data: Vec<u8>,
+    /// ACK (true) or NACK for each byte of `data`.
+    pub acks: Vec<bool>,
+    /// Ended by a repeated start rather than a stop.
+    pub repeated_start: bool
+}
+
+pub struct Transactions<'a, T: Transport + 'a> {
+    sniffer: Sniffer<'a, T>,
+    // Bytes seen since the last start, address byte first, with their
+    // ACKs.
+    current: Option<(Vec<u8>, Vec<bool>)>
+}
+
+impl<'a, T: Transport> Transactions<'a, T> {
+    // Turn the bytes since the last start into a transaction, if an
+    // address was seen.
+    fn finish(&mut self, repeated_start: bool) -> Option<Transaction> {
+        let (bytes, mut acks) = self.current.take()?;
+        let (&first, data) = bytes.split_first()?;
+        acks.resize(bytes.len(), false);
+        Some(Transaction { addr: first >> 1,
+                           read: first & 1 == 1,
+                           addr_ack: acks[0],
+                           data: data.to_vec(),
+                           acks: acks[1..].to_vec(),
+                           repeated_start: repeated_start })
+    }
+}
+
+/// Yields complete transactions until the port read times out, a
+/// transaction still in progress carries over to the next call.
+impl<'a, T: Transport> Iterator for Transactions<'a, T> {
+    type Item = Result<Transaction, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let event = match self.sniffer.next()? {
+                Ok(event) => event,
+                Err(e) => return Some(Err(e))
+            };
+            match event {
+                SnifferEvent::Start => {
+                    let done = self.finish(true);
+                    self.current = Some((Vec::new(), Vec::new()));
+                    if let Some(transaction) = done {
+                        return Some(Ok(transaction));
+                    }
+                }
+                SnifferEvent::Stop => {
+                    if let Some(transaction) = self.finish(false) {
+                        return Some(Ok(transaction));
+                    }
+                }
+                SnifferEvent::Byte(byte) => {
+                    if let Some((ref mut bytes, _)) = self.current {
+                        bytes.push(byte);
+                    }
+                }
+                SnifferEvent::Ack | SnifferEvent::Nack => {
+                    if let Some((_, ref mut acks)) = self.current {
+                        acks.push(event == SnifferEvent::Ack);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T: Transport> Drop for I2CConn<T> {
+    fn drop(&mut self) {
+        let _ = self.set_peripherals(false, false, false, false);
         let _ = self.call(&Message::ExitToBBIO);
     }
 }
@@ -144,7 +572,6 @@ impl Drop for I2CConn {
 // Due to a typo this was previously command 0110.
 //
 // 010100xy - Pull up voltage select (BPV4 only)- x=5v y=3.3v
-// Unimplemented yet.
 // Sending 01010010 connects VEXTERN to the 5V rail (disconnects
 // 3.3v), while 01010001 connects VEXTERN to the 3.3V rail (and
 // disconnects 5V). 01010000 disconnects both rails. The Bus Pirate
@@ -156,6 +583,7 @@ impl Drop for I2CConn {
 // 011000xx - Set I2C speed, 3=~400kHz, 2=~100kHz, 1=~50kHz, 0=~5kHz
 // (updated in v4.2 firmware)
 // 0110000x - Set I2C speed, 1=high (50kHz) 0=low (5kHz)
+// (before v4.2, same bits for the two speeds it has)
 //
 // The lower bits of the speed command determine the I2C bus
 // speed. Binary mode currently uses the software I2C library, though
@@ -240,7 +668,7 @@ pub enum Message {
     Configure(bool, bool, bool, bool),
     PullUpSelect(PullUp),
     SetSpeed(Speed),
-    WriteThenRead(Vec<u8>, u8)
+    WriteThenRead(Vec<u8>, u16)
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -291,10 +719,11 @@ impl FromStr for Speed {
 }
 
 use self::Message::*;
-use std::iter::Extend;
 impl Message {
-    pub fn send(&self) -> Vec<u8> {
-        match *self {
+    /// The bytes to send, or an `InvalidInput` error when the message
+    /// can't be encoded.
+    pub fn send(&self) -> io::Result<Vec<u8>> {
+        Ok(match *self {
             ExitToBBIO => vec![0b00000000],
             I2CVSN => vec![0b00000001],
             StartBit => vec![0b00000010],
@@ -304,29 +733,28 @@ impl Message {
             NackBit => vec![0b00000111],
             StartBusSniffer => vec![0b00001111],
             ExitBusSniffer => vec![0b00001111],
-            BulkWrite(ref bytes) if bytes.len() > 0 && bytes.len() <= 16 => {
-                let mut buf: Vec<u8> = vec![0b0001_0000 |
-                                            0b0001_1111 &
-                                            (bytes.len() - 1) as u8];
-                buf.extend(bytes);
-                buf
-            },
-            Configure(power, pullups, aux, cs) => {
-                let mut cmd = 0b0100_0000;
-                if power { cmd |= 0b1000; }
-                if pullups { cmd |= 0b0100; }
-                if aux { cmd |= 0b0010; }
-                if cs {cmd |= 0b0001; }
-                vec![cmd]
-            },
+            BulkWrite(ref bytes) => return protocol::bulk(0b0001_0000, bytes),
+            Configure(power, pullups, aux, cs) =>
+                protocol::configure(power, pullups, aux, cs),
             PullUpSelect(pullup) => {
                 vec![0b0101_0000 | pullup as u8]
             },
             SetSpeed(speed) => {
                 vec![0b0110_0000 | speed as u8]
             },
-            _ => unimplemented!()
-        }
+            WriteThenRead(ref bytes, read_len)
+                if bytes.len() > MAX_WRITE_THEN_READ || read_len as usize > MAX_WRITE_THEN_READ =>
+                return Err(io::Error::new(ErrorKind::InvalidInput,
+                                          "write then read is limited to 4096 bytes")),
+            WriteThenRead(ref bytes, read_len) => {
+                let write_len = bytes.len() as u16;
+                let mut buf = vec![0b0000_1000,
+                                   (write_len >> 8) as u8, write_len as u8,
+                                   (read_len >> 8) as u8, read_len as u8];
+                buf.extend(bytes);
+                buf
+            }
+        })
     }
 
     pub fn expect(&self) -> Option<Vec<u8>> {
@@ -345,3 +773,13 @@ impl Message {
         }
     }
 }
+
+impl Command for Message {
+    fn send(&self) -> io::Result<Vec<u8>> {
+        Message::send(self)
+    }
+
+    fn expect(&self) -> Option<Vec<u8>> {
+        Message::expect(self)
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::str::FromStr;
+use std::result::Result;
+
+use failure::Error;
+
+/// A major.minor version number, ordered so capabilities can be gated
+/// with comparisons like `info.firmware >= Version::new(4, 2)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32
+}
+
+impl Version {
+    pub fn new(major: u32, minor: u32) -> Self {
+        Self { major: major, minor: minor }
+    }
+
+    // Parse "6.2-beta1" into the version and whatever trails it.
+    fn parse(s: &str) -> Option<(Self, &str)> {
+        let (major, rest) = s.split_once('.')?;
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let (minor, suffix) = rest.split_at(digits);
+        Some((Self::new(major.parse().ok()?, minor.parse().ok()?), suffix))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}.{}", self.major, self.minor)
+    }
+}
+
+/// What the reset banner says about the hardware and firmware:
+///
+/// ```text
+/// Bus Pirate v3.5
+/// Firmware v5.10 (r559)  Bootloader v4.4
+/// DEVID:0x0447 REVID:0x3046 (24FJ64GA002 B8)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PirateInfo {
+    /// The hardware revision: "v4", "v3.5", "v3.b", "v2go", ...
+    pub hardware: String,
+    pub firmware: Version,
+    /// Anything straight after the firmware version, "-beta1" in
+    /// "v6.2-beta1".
+    pub firmware_suffix: String,
+    /// The SVN revision the firmware was built from.
+    pub revision: Option<u32>,
+    pub bootloader: Option<Version>,
+    pub devid: Option<u16>,
+    pub revid: Option<u16>,
+    /// The PIC part, "24FJ256GB106".
+    pub mcu: Option<String>
+}
+
+#[derive(Debug, Fail)]
+pub enum InfoError {
+    #[fail(display="no hardware and firmware version in banner {:?}", banner)]
+    Unparseable { banner: String }
+}
+
+bitflags! {
+    /// The commands that depend on the hardware or firmware version.
+    pub struct Capabilities: u8 {
+        /// Pull-up voltage select (010100xy), v4 hardware only.
+        const PULLUP_SELECT   = 0b0000_0001;
+        /// The 100kHz and 400kHz I2C speeds (011000xx), firmware 4.2
+        /// and later. Before that there's only 5kHz and 50kHz.
+        const I2C_FAST_SPEEDS = 0b0000_0010;
+        /// The binary self-tests, v2go and v3 hardware only.
+        const SELF_TEST       = 0b0000_0100;
+        /// Raw-wire bulk bits (0011xxxx), firmware 4.5 and later.
+        const BULK_BITS       = 0b0000_1000;
+    }
+}
+
+/// A command the connected Pirate can't do, or one that depends on
+/// the version when that hasn't been read.
+#[derive(Debug, Fail)]
+pub struct Unsupported {
+    pub command: &'static str,
+    pub missing: Capabilities,
+    /// The hardware and firmware versions, None when they're unknown.
+    pub hardware: Option<String>,
+    pub firmware: Option<Version>
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.hardware, self.firmware) {
+            (&Some(ref hardware), Some(firmware)) =>
+                write!(f, "{} isn't supported by a Bus Pirate {} with firmware {}",
+                       self.command, hardware, firmware),
+            _ => write!(f, "{} needs the Bus Pirate version, which hasn't been read",
+                        self.command)
+        }
+    }
+}
+
+impl PirateInfo {
+    /// Parse a reset banner, as returned by `BusPirate::read_vsn`.
+    /// Lines that aren't recognised are skipped, but the hardware and
+    /// firmware versions have to be there.
+    pub fn parse(banner: &str) -> Result<Self, Error> {
+        let mut hardware = None;
+        let mut firmware = None;
+        let mut revision = None;
+        let mut bootloader = None;
+        let mut devid = None;
+        let mut revid = None;
+        let mut mcu = None;
+
+        for line in banner.lines().map(str::trim) {
+            if let Some((_, hw)) = line.split_once("Bus Pirate ") {
+                if hw.starts_with('v') {
+                    hardware = Some(hw.trim().to_string());
+                }
+            } else if let Some((_, rest)) = line.split_once("Firmware v") {
+                // "6.2-beta1 r1981", "5.10 (r559)  Bootloader v4.4" or
+                // "7.0 - goo.gl/gCzQnW [HiZ ...] Bootloader v4.4"
+                let (fw, boot) = match rest.split_once("Bootloader v") {
+                    Some((fw, boot)) => (fw, Some(boot)),
+                    None => (rest, None)
+                };
+                let mut words = fw.split_whitespace();
+                firmware = words.next()
+                    .and_then(Version::parse)
+                    .map(|(vsn, suffix)| (vsn, suffix.to_string()));
+                revision = words
+                    .map(|w| w.trim_matches(|c| c == '(' || c == ')'))
+                    .find_map(|w| w.strip_prefix('r').and_then(|r| r.parse().ok()));
+                bootloader = boot.and_then(Version::parse).map(|(vsn, _)| vsn);
+            } else if line.starts_with("DEVID:") {
+                // "DEVID:0x1019 REVID:0x0004 (24FJ256GB106 UNK)"
+                for word in line.split_whitespace() {
+                    if let Some(hex) = word.strip_prefix("DEVID:0x") {
+                        devid = u16::from_str_radix(hex, 16).ok();
+                    } else if let Some(hex) = word.strip_prefix("REVID:0x") {
+                        revid = u16::from_str_radix(hex, 16).ok();
+                    }
+                }
+                mcu = line.split_once('(')
+                    .and_then(|(_, part)| part.split_whitespace().next())
+                    .map(|part| part.trim_end_matches(')').to_string());
+            }
+        }
+
+        match (hardware, firmware) {
+            (Some(hardware), Some((firmware, firmware_suffix))) =>
+                Ok(Self { hardware: hardware,
+                          firmware: firmware,
+                          firmware_suffix: firmware_suffix,
+                          revision: revision,
+                          bootloader: bootloader,
+                          devid: devid,
+                          revid: revid,
+                          mcu: mcu }),
+            _ => Err(InfoError::Unparseable { banner: banner.to_string() }.into())
+        }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        let mut caps = Capabilities::empty();
+        if self.hardware.starts_with("v4") {
+            caps |= Capabilities::PULLUP_SELECT;
+        }
+        if self.firmware >= Version::new(4, 2) {
+            caps |= Capabilities::I2C_FAST_SPEEDS;
+        }
+        if self.firmware >= Version::new(4, 5) {
+            caps |= Capabilities::BULK_BITS;
+        }
+        if self.hardware.starts_with("v2go") || self.hardware.starts_with("v3") {
+            caps |= Capabilities::SELF_TEST;
+        }
+        caps
+    }
+
+    /// An `Unsupported` error for `command` unless the Pirate has all
+    /// of `needed`.
+    pub fn require(&self, needed: Capabilities, command: &'static str)
+                   -> Result<(), Unsupported> {
+        let missing = needed - self.capabilities();
+        if !missing.is_empty() {
+            return Err(Unsupported { command: command,
+                                     missing: missing,
+                                     hardware: Some(self.hardware.clone()),
+                                     firmware: Some(self.firmware) });
+        }
+        Ok(())
+    }
+}
+
+/// What a connection can do given the `PirateInfo` it was opened
+/// with. `BusPirate::enter_bio_mode` only reads the version when it
+/// finds the Pirate at the terminal, and `BBIOConn::read_info` reads it
+/// any time. Without it nothing version dependent is allowed.
+pub fn capabilities(info: Option<&PirateInfo>) -> Capabilities {
+    info.map_or(Capabilities::empty(), PirateInfo::capabilities)
+}
+
+/// `PirateInfo::require` for a connection that may not know its
+/// Pirate. An unknown version has none of `needed`.
+pub fn require(info: Option<&PirateInfo>, needed: Capabilities, command: &'static str)
+               -> Result<(), Unsupported> {
+    match info {
+        Some(info) => info.require(needed, command),
+        None => Err(Unsupported { command: command,
+                                  missing: needed,
+                                  hardware: None,
+                                  firmware: None })
+    }
+}
+
+impl FromStr for PirateInfo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
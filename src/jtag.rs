@@ -0,0 +1,477 @@
+use serial::SystemPort;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::result::Result;
+
+use failure::Error;
+
+use super::bbio::adc_to_volts;
+use super::info::{self, Capabilities, PirateInfo};
+use super::protocol::{self, Command, InvalidReply};
+use super::transport::Transport;
+
+/// The OpenOCD JTAG mode. Besides the raw TAP shift it tracks the TAP
+/// controller state so it can walk the state machine for IR/DR scans
+/// itself.
+pub struct JtagConn<T: Transport = SystemPort> {
+    port: T,
+    info: Option<PirateInfo>,
+    // None until the first reset, the TAP could be in any state.
+    state: Option<TapState>,
+}
+
+#[derive(Debug, Fail)]
+enum CallError {
+    #[fail(display="can't shift {} bits from {} bytes of TDI and {} of TMS",
+           bits, tdi, tms)]
+    InvalidLength { bits: usize, tdi: usize, tms: usize },
+    #[fail(display="can't scan {} bits from {} bytes", bits, bytes)]
+    InvalidScan { bits: usize, bytes: usize },
+    #[fail(display="no end of the scan chain after {} devices", devices)]
+    BrokenChain { devices: usize }
+}
+
+// The largest TAP shift OpenOCD sends in one command.
+const MAX_SHIFT_BITS: usize = 0x2000;
+// How many devices idcodes() reads before giving up on the chain.
+const MAX_CHAIN: usize = 32;
+
+impl<T: Transport> JtagConn<T> {
+    pub fn new(port: T) -> Self {
+        Self::with_info(port, None)
+    }
+
+    /// A connection that knows which Pirate it's talking to.
+    pub fn with_info(port: T, info: Option<PirateInfo>) -> Self {
+        Self { port: port, info: info, state: None }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        info::capabilities(self.info.as_ref())
+    }
+
+    fn call(&mut self, msg: &Message) -> Result<Vec<u8>, Error> {
+        protocol::call(&mut self.port, msg)
+    }
+
+    pub fn set_port_mode(&mut self, mode: PortMode) -> Result<(), Error> {
+        self.call(&Message::SetPortMode(mode))?;
+        Ok(())
+    }
+
+    pub fn set_feature(&mut self, feature: Feature, on: bool) -> Result<(), Error> {
+        self.call(&Message::SetFeature(feature, on))?;
+        Ok(())
+    }
+
+    pub fn read_adcs(&mut self) -> Result<Adcs, Error> {
+        self.port.write_all(&Message::ReadAdcs.send()?)?;
+        let mut reply = [0; 10];
+        self.port.read_exact(&mut reply)?;
+        if reply[0] != 0b0000_0011 {
+            return Err(InvalidReply { sent: Message::ReadAdcs,
+                                      expected: vec![0b0000_0011],
+                                      received: reply.to_vec() }.into());
+        }
+        let volts = |i: usize| adc_to_volts((reply[i] as u16) << 8 | reply[i + 1] as u16);
+        Ok(Adcs { adc: volts(2),
+                  vpullup: volts(4),
+                  v3_3: volts(6),
+                  v5_0: volts(8) })
+    }
+
+    /// Switch the Pirate's UART between 115200 (Normal) and 1000000
+    /// (Fast) baud. `reconfigure` is called with the new rate once the
+    /// Pirate has switched and must set the host side to match. Go back
+    /// to Normal before dropping the connection, the rest of the binary
+    /// modes expect 115200.
+    pub fn set_serial_speed<F>(&mut self, speed: SerialSpeed, reconfigure: F)
+                               -> Result<(), Error>
+        where F: FnOnce(&mut T, u32) -> Result<(), Error>
+    {
+        self.port.write_all(&Message::SetSerialSpeed(speed).send()?)?;
+        self.port.flush()?;
+        reconfigure(&mut self.port, speed.baud())?;
+        self.call(&Message::SerialSpeedAck(speed))?;
+        Ok(())
+    }
+
+    /// Clock `bits` bits out of `tdi` and `tms` (LSB of the first byte
+    /// first) and return what was sampled on TDO, packed the same way.
+    pub fn tap_shift(&mut self, bits: usize, tdi: &[u8], tms: &[u8])
+                     -> Result<Vec<u8>, Error> {
+        let bytes = bits.div_ceil(8);
+        if tdi.len() < bytes || tms.len() < bytes {
+            return Err(CallError::InvalidLength { bits: bits,
+                                                  tdi: tdi.len(),
+                                                  tms: tms.len() }.into());
+        }
+        let mut tdo = Vec::with_capacity(bytes);
+        let mut done = 0;
+        while done < bits {
+            let chunk = (bits - done).min(MAX_SHIFT_BITS);
+            let (from, to) = (done / 8, (done + chunk).div_ceil(8));
+            let msg = Message::TapShift(chunk as u16,
+                                        tdi[from..to].to_vec(),
+                                        tms[from..to].to_vec());
+            let sent = msg.send()?;
+            self.port.write_all(&sent)?;
+            let mut reply = vec![0; 3 + to - from];
+            self.port.read_exact(&mut reply)?;
+            // The reply starts with the command and bit count echoed.
+            if reply[..3] != sent[..3] {
+                return Err(InvalidReply { sent: msg,
+                                          expected: sent[..3].to_vec(),
+                                          received: reply }.into());
+            }
+            tdo.extend(&reply[3..]);
+            done += chunk;
+        }
+        Ok(tdo)
+    }
+
+    // Clock a TMS/TDI bit sequence, following it through the state
+    // machine.
+    fn clock(&mut self, tms: &[bool], tdi: &[bool]) -> Result<Vec<bool>, Error> {
+        let tdo = self.tap_shift(tms.len(), &pack(tdi), &pack(tms))?;
+        self.state = self.state
+            .map(|state| tms.iter().fold(state, |state, &tms| state.next(tms)));
+        Ok(unpack(&tdo, tms.len()))
+    }
+
+    /// The TAP controller state, None before the first reset.
+    pub fn state(&self) -> Option<TapState> {
+        self.state
+    }
+
+    /// Put the TAP into Test-Logic-Reset with five TMS high clocks,
+    /// which works from any state.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.clock(&[true; 5], &[false; 5])?;
+        self.state = Some(TapState::TestLogicReset);
+        Ok(())
+    }
+
+    /// Walk the TAP to `target` by the shortest path.
+    pub fn goto_state(&mut self, target: TapState) -> Result<(), Error> {
+        let tms = self.current_state()?.path_to(target);
+        let tdi = vec![false; tms.len()];
+        self.clock(&tms, &tdi)?;
+        Ok(())
+    }
+
+    fn current_state(&mut self) -> Result<TapState, Error> {
+        if self.state.is_none() {
+            self.reset()?;
+        }
+        Ok(self.state.unwrap_or(TapState::TestLogicReset))
+    }
+
+    /// Shift `bits` bits of `tdi` through the instruction register and
+    /// return the bits captured, ending in Run-Test/Idle.
+    pub fn scan_ir(&mut self, bits: usize, tdi: &[u8]) -> Result<Vec<u8>, Error> {
+        self.scan(TapState::ShiftIR, bits, tdi)
+    }
+
+    /// Shift `bits` bits of `tdi` through the selected data register
+    /// and return the bits captured, ending in Run-Test/Idle.
+    pub fn scan_dr(&mut self, bits: usize, tdi: &[u8]) -> Result<Vec<u8>, Error> {
+        self.scan(TapState::ShiftDR, bits, tdi)
+    }
+
+    fn scan(&mut self, shift: TapState, bits: usize, tdi: &[u8])
+            -> Result<Vec<u8>, Error> {
+        if bits == 0 || tdi.len() < bits.div_ceil(8) {
+            return Err(CallError::InvalidScan { bits: bits,
+                                                bytes: tdi.len() }.into());
+        }
+        // Walk to the shift state, leave it to Exit1 on the last bit
+        // then go through Update to Run-Test/Idle.
+        let mut tms = self.current_state()?.path_to(shift);
+        let lead = tms.len();
+        let mut tdi_bits = vec![false; lead];
+        tdi_bits.extend(unpack(tdi, bits));
+        tms.extend((0..bits).map(|i| i == bits - 1));
+        tms.extend(&[true, false]);
+        tdi_bits.extend(&[false, false]);
+        let tdo = self.clock(&tms, &tdi_bits)?;
+        Ok(pack(&tdo[lead..lead + bits]))
+    }
+
+    /// Read the IDCODE of every device on the scan chain, nearest TDO
+    /// first. After a reset each device selects its IDCODE register,
+    /// or BYPASS if it has none, which shows up as None.
+    pub fn idcodes(&mut self) -> Result<Vec<Option<IdCode>>, Error> {
+        self.reset()?;
+        let bits = (MAX_CHAIN + 1) * 32;
+        let tdo = unpack(&self.scan_dr(bits, &vec![0xFF; bits / 8])?, bits);
+        let mut devices = Vec::new();
+        let mut i = 0;
+        // Shifting in ones, 32 of them in a row means the chain is done.
+        while i + 32 <= bits {
+            if !tdo[i] {
+                devices.push(None);
+                i += 1;
+                continue;
+            }
+            let word = tdo[i..i + 32].iter().rev()
+                .fold(0u32, |word, &bit| word << 1 | bit as u32);
+            if word == 0xFFFF_FFFF {
+                return Ok(devices);
+            }
+            devices.push(Some(IdCode(word)));
+            i += 32;
+        }
+        Err(CallError::BrokenChain { devices: devices.len() }.into())
+    }
+}
+
+impl<T: Transport> Drop for JtagConn<T> {
+    fn drop(&mut self) {
+        let _ = self.call(&Message::SetPortMode(PortMode::HiZ));
+        let _ = self.call(&Message::ExitToBBIO);
+    }
+}
+
+fn pack(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|byte| byte.iter().enumerate()
+             .fold(0, |acc, (i, &bit)| acc | (bit as u8) << i))
+        .collect()
+}
+
+fn unpack(bytes: &[u8], bits: usize) -> Vec<bool> {
+    (0..bits).map(|i| bytes[i / 8] & (1 << (i % 8)) != 0).collect()
+}
+
+/// The IEEE 1149.1 TAP controller states.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TapState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDR,
+    CaptureDR,
+    ShiftDR,
+    Exit1DR,
+    PauseDR,
+    Exit2DR,
+    UpdateDR,
+    SelectIR,
+    CaptureIR,
+    ShiftIR,
+    Exit1IR,
+    PauseIR,
+    Exit2IR,
+    UpdateIR
+}
+
+impl TapState {
+    /// The state after one TCK with TMS at `tms`.
+    pub fn next(self, tms: bool) -> TapState {
+        use self::TapState::*;
+        match (self, tms) {
+            (TestLogicReset, false) => RunTestIdle,
+            (TestLogicReset, true) => TestLogicReset,
+            (RunTestIdle, false) => RunTestIdle,
+            (RunTestIdle, true) => SelectDR,
+            (SelectDR, false) => CaptureDR,
+            (SelectDR, true) => SelectIR,
+            (CaptureDR, false) | (ShiftDR, false) | (Exit2DR, false) => ShiftDR,
+            (CaptureDR, true) | (ShiftDR, true) => Exit1DR,
+            (Exit1DR, false) | (PauseDR, false) => PauseDR,
+            (Exit1DR, true) | (Exit2DR, true) => UpdateDR,
+            (PauseDR, true) => Exit2DR,
+            (UpdateDR, false) | (UpdateIR, false) => RunTestIdle,
+            (UpdateDR, true) | (UpdateIR, true) => SelectDR,
+            (SelectIR, false) => CaptureIR,
+            (SelectIR, true) => TestLogicReset,
+            (CaptureIR, false) | (ShiftIR, false) | (Exit2IR, false) => ShiftIR,
+            (CaptureIR, true) | (ShiftIR, true) => Exit1IR,
+            (Exit1IR, false) | (PauseIR, false) => PauseIR,
+            (Exit1IR, true) | (Exit2IR, true) => UpdateIR,
+            (PauseIR, true) => Exit2IR
+        }
+    }
+
+    /// The shortest TMS sequence that takes the TAP from this state to
+    /// `target`.
+    pub fn path_to(self, target: TapState) -> Vec<bool> {
+        let mut from: [Option<(TapState, bool)>; 16] = [None; 16];
+        let mut queue = VecDeque::new();
+        queue.push_back(self);
+        while let Some(state) = queue.pop_front() {
+            if state == target {
+                break;
+            }
+            for &tms in &[false, true] {
+                let next = state.next(tms);
+                if next != self && from[next as usize].is_none() {
+                    from[next as usize] = Some((state, tms));
+                    queue.push_back(next);
+                }
+            }
+        }
+        let mut path = Vec::new();
+        let mut state = target;
+        while state != self {
+            let (prev, tms) = from[state as usize]
+                .expect("every TAP state is reachable");
+            path.push(tms);
+            state = prev;
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// A 32-bit JTAG IDCODE.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IdCode(pub u32);
+
+impl IdCode {
+    pub fn version(&self) -> u8 {
+        (self.0 >> 28) as u8
+    }
+
+    pub fn part(&self) -> u16 {
+        (self.0 >> 12) as u16
+    }
+
+    /// The JEP106 manufacturer code: continuation count in the upper
+    /// four bits, ID in the lower seven.
+    pub fn manufacturer(&self) -> u16 {
+        ((self.0 >> 1) & 0x7FF) as u16
+    }
+}
+
+impl fmt::Display for IdCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:08X}", self.0)
+    }
+}
+
+/// The four voltages read_adcs reports.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Adcs {
+    /// The voltage probe (ADC pin).
+    pub adc: f64,
+    pub vpullup: f64,
+    pub v3_3: f64,
+    pub v5_0: f64
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PortMode {
+    HiZ = 0,
+    Jtag = 1,
+    JtagOpenDrain = 2
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Feature {
+    Led = 0x01,
+    Vreg = 0x02,
+    Trst = 0x04,
+    Srst = 0x08,
+    Pullup = 0x10
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SerialSpeed {
+    Normal = 0,
+    Fast = 1
+}
+
+impl SerialSpeed {
+    pub fn baud(&self) -> u32 {
+        match *self {
+            SerialSpeed::Normal => 115_200,
+            SerialSpeed::Fast => 1_000_000
+        }
+    }
+}
+
+// The OpenOCD mode isn't on the binary mode pages, this follows the
+// firmware and OpenOCD's buspirate driver.
+//
+// 00000000 - Exit to bitbang mode, responds "BBIO1"
+//
+// 00000001 mmmmmmmm - Port mode
+// 0 HiZ, 1 JTAG (push-pull), 2 JTAG open drain. No reply.
+//
+// 00000010 ffffffff aaaaaaaa - Feature
+// Turn feature f (0x01 LED, 0x02 power supplies, 0x04 TRST, 0x08
+// SRST, 0x10 pull-ups) off (a=0) or on (a=1). No reply.
+//
+// 00000011 - Read ADCs
+// Responds 0x03, a count (8) and four ADC readings, high byte first:
+// the ADC probe, Vpullup, 3.3V and 5V supplies. Volts are
+// (ADC/1024)*6.6.
+//
+// 00000101 nnnnnnnn nnnnnnnn [tdi tms]... - TAP shift
+// Clock n bits (high byte first), sending ceil(n/8) pairs of TDI and
+// TMS bytes, LSB first. Responds 0x05, n and the ceil(n/8) TDO bytes.
+//
+// 00000111 ssssssss - UART speed
+// 0 sets 115200, 1 sets 1000000 baud. The Pirate switches and waits
+// for 0xAA 0x55 at the new rate, then responds 0x07 s.
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    ExitToBBIO,
+    SetPortMode(PortMode),
+    SetFeature(Feature, bool),
+    ReadAdcs,
+    TapShift(u16, Vec<u8>, Vec<u8>),
+    SetSerialSpeed(SerialSpeed),
+    SerialSpeedAck(SerialSpeed)
+}
+
+use self::Message::*;
+impl Message {
+    /// The bytes to send, or an `InvalidInput` error when the message
+    /// can't be encoded.
+    pub fn send(&self) -> io::Result<Vec<u8>> {
+        Ok(match *self {
+            ExitToBBIO => vec![0b00000000],
+            SetPortMode(mode) => vec![0b00000001, mode as u8],
+            SetFeature(feature, on) => vec![0b00000010, feature as u8, on as u8],
+            ReadAdcs => vec![0b00000011],
+            TapShift(bits, ref tdi, ref tms)
+                if tdi.len() != tms.len() || tdi.len() != (bits as usize).div_ceil(8) =>
+                return Err(io::Error::new(ErrorKind::InvalidInput,
+                                          "TDI and TMS must both cover the bit count")),
+            TapShift(bits, ref tdi, ref tms) => {
+                let mut buf = vec![0b00000101, (bits >> 8) as u8, bits as u8];
+                for (tdi, tms) in tdi.iter().zip(tms) {
+                    buf.push(*tdi);
+                    buf.push(*tms);
+                }
+                buf
+            },
+            SetSerialSpeed(speed) => vec![0b00000111, speed as u8],
+            SerialSpeedAck(_) => vec![0xAA, 0x55]
+        })
+    }
+
+    pub fn expect(&self) -> Option<Vec<u8>> {
+        match *self {
+            ExitToBBIO => Some(vec![b'B', b'B', b'I', b'O', b'1']),
+            SetPortMode(_) => Some(vec![]),
+            SetFeature(_, _) => Some(vec![]),
+            SerialSpeedAck(speed) => Some(vec![0b00000111, speed as u8]),
+            _ => None
+        }
+    }
+}
+
+impl Command for Message {
+    fn send(&self) -> io::Result<Vec<u8>> {
+        Message::send(self)
+    }
+
+    fn expect(&self) -> Option<Vec<u8>> {
+        Message::expect(self)
+    }
+}
@@ -1,13 +1,29 @@
 #![feature(conservative_impl_trait)]
 extern crate serial_ports;
 extern crate serial;
+extern crate embedded_hal;
 
 #[macro_use] extern crate failure;
+#[macro_use] extern crate bitflags;
 
 mod device;
 mod pirate;
+mod transport;
+mod protocol;
 pub mod i2c;
+pub mod spi;
+pub mod uart;
+pub mod onewire;
+pub mod rawwire;
+pub mod jtag;
 pub mod bbio;
+pub mod info;
+pub mod hal;
+pub mod eeprom;
+pub mod spiflash;
+pub mod sim;
 
-pub use pirate::BusPirate;
+pub use pirate::{BusPirate, PirateState};
 pub use device::{Device, Devices};
+pub use transport::Transport;
+pub use info::PirateInfo;
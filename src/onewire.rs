@@ -0,0 +1,318 @@
+use serial::SystemPort;
+use std::fmt;
+use std::io;
+use std::result::Result;
+
+use failure::Error;
+
+use super::info::{self, Capabilities, PirateInfo};
+use super::protocol::{self, Command};
+use super::transport::Transport;
+
+pub struct OneWireConn<T: Transport = SystemPort> {
+    port: T,
+    info: Option<PirateInfo>
+}
+
+/// A 64-bit 1-Wire ROM ID. Byte 0 (the least significant) is the
+/// family code, bytes 1-6 the serial number and byte 7 the CRC8 of the
+/// other seven, which is also the order they go over the wire.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RomId(pub u64);
+
+impl RomId {
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        RomId(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bytes(&self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn family(&self) -> u8 {
+        self.bytes()[0]
+    }
+
+    pub fn serial(&self) -> u64 {
+        (self.0 >> 8) & 0xFFFF_FFFF_FFFF
+    }
+
+    pub fn crc(&self) -> u8 {
+        self.bytes()[7]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        crc8(&self.bytes()[..7]) == self.crc()
+    }
+}
+
+impl fmt::Display for RomId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self.bytes();
+        write!(f, "{:02X}-{:012X}-{:02X}", bytes[0], self.serial(), bytes[7])
+    }
+}
+
+/// The Dallas/Maxim 1-Wire CRC8 (x^8 + x^5 + x^4 + 1, LSB first).
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold((crc, byte), |(crc, byte), _| {
+            let mix = (crc ^ byte) & 0x01;
+            let crc = if mix == 1 { (crc >> 1) ^ 0x8C } else { crc >> 1 };
+            (crc, byte >> 1)
+        }).0
+    })
+}
+
+#[derive(Debug, Fail)]
+enum CallError {
+    #[fail(display="can't send {} bytes in one bulk write (1-16)", len)]
+    InvalidLength { len: usize },
+    #[fail(display="no presence pulse after the bus reset")]
+    NoPresence
+}
+
+/// The ROMs a search turned up. Ones that fail their CRC check, from
+/// noise or a collision the search couldn't untangle, are kept apart
+/// from the rest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchResult {
+    pub roms: Vec<RomId>,
+    pub bad_crc: Vec<RomId>
+}
+
+// ROM level commands the search macros don't cover.
+const MATCH_ROM: u8 = 0x55;
+const SKIP_ROM: u8 = 0xCC;
+
+impl<T: Transport> OneWireConn<T> {
+    pub fn new(port: T) -> Self {
+        Self::with_info(port, None)
+    }
+
+    /// A connection that knows which Pirate it's talking to.
+    pub fn with_info(port: T, info: Option<PirateInfo>) -> Self {
+        Self { port: port, info: info }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        info::capabilities(self.info.as_ref())
+    }
+
+    pub fn test(&mut self) -> Result<(), Error> {
+        self.call(&Message::OneWireVSN)?;
+        Ok(())
+    }
+
+    fn call(&mut self, msg: &Message) -> Result<Vec<u8>, Error> {
+        protocol::call(&mut self.port, msg)
+    }
+
+    pub fn set_peripherals(&mut self, power: bool, pullups: bool,
+                           aux: bool, cs: bool) -> Result<(), Error> {
+        self.call(&Message::Configure(power, pullups, aux, cs))?;
+        Ok(())
+    }
+
+    /// Send a bus reset pulse.
+    ///
+    /// The binary mode acknowledges every reset the same way whether or
+    /// not anything answered it, use `presence` to find out if there
+    /// is a device on the bus.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.call(&Message::Reset)?;
+        Ok(())
+    }
+
+    /// Check for at least one device on the bus by running a ROM
+    /// search, the only way to see presence pulses from binary mode.
+    /// A ROM that fails its CRC check still means something answered.
+    pub fn presence(&mut self) -> Result<bool, Error> {
+        let found = self.search_rom()?;
+        Ok(!found.roms.is_empty() || !found.bad_crc.is_empty())
+    }
+
+    // Reset the bus, failing if nothing is there to address.
+    fn reset_for_rom_command(&mut self) -> Result<(), Error> {
+        if !self.presence()? {
+            return Err(CallError::NoPresence.into());
+        }
+        self.reset()
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, Error> {
+        self.port.write_all(&Message::ReadByte.send()?)?;
+        let mut byte = [0; 1];
+        self.port.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    pub fn read(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        (0..len).map(|_| self.read_byte()).collect()
+    }
+
+    /// Write 1-16 bytes in one bulk write.
+    pub fn bulk_write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if bytes.is_empty() || bytes.len() > 16 {
+            return Err(CallError::InvalidLength { len: bytes.len() }.into());
+        }
+        self.call(&Message::BulkWrite(bytes.to_vec()))?;
+        Ok(())
+    }
+
+    /// Write any number of bytes, 16 at a time.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        for chunk in bytes.chunks(16) {
+            self.bulk_write(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Reset the bus and address a single device with MATCH ROM. The
+    /// next bytes written go to that device's function commands.
+    pub fn select(&mut self, rom: &RomId) -> Result<(), Error> {
+        self.reset_for_rom_command()?;
+        let mut cmd = vec![MATCH_ROM];
+        cmd.extend(&rom.bytes());
+        self.write(&cmd)
+    }
+
+    /// Reset the bus and address every device at once with SKIP ROM.
+    pub fn select_all(&mut self) -> Result<(), Error> {
+        self.reset_for_rom_command()?;
+        self.bulk_write(&[SKIP_ROM])
+    }
+
+    /// Enumerate every device on the bus (SEARCH ROM, 0xF0).
+    pub fn search_rom(&mut self) -> Result<SearchResult, Error> {
+        self.search(Message::SearchROM)
+    }
+
+    /// Enumerate the devices with an alarm condition (ALARM SEARCH,
+    /// 0xEC).
+    pub fn search_alarm(&mut self) -> Result<SearchResult, Error> {
+        self.search(Message::SearchAlarm)
+    }
+
+    fn search(&mut self, msg: Message) -> Result<SearchResult, Error> {
+        self.call(&msg)?;
+        let mut found = SearchResult::default();
+        // The list ends with a ROM of all 1s.
+        loop {
+            let mut bytes = [0; 8];
+            self.port.read_exact(&mut bytes)?;
+            if bytes == [0xFF; 8] {
+                break;
+            }
+            let rom = RomId::from_bytes(bytes);
+            if rom.is_valid() {
+                found.roms.push(rom);
+            } else {
+                found.bad_crc.push(rom);
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl<T: Transport> Drop for OneWireConn<T> {
+    fn drop(&mut self) {
+        let _ = self.call(&Message::Configure(false,false,false,false));
+        let _ = self.call(&Message::ExitToBBIO);
+    }
+}
+
+// 00000000 - Reset to raw bitbang mode, responds "BBIOx"
+// This command resets the Bus Pirate into raw bitbang mode from the
+// user terminal. It also resets to raw bitbang mode from raw 1-Wire
+// mode, or any other protocol mode. This command always returns a
+// five byte bitbang version string "BBIOx", where x is the current
+// bitbang protocol version (currently 1).
+//
+// 00000001 - Mode version string (1W01)
+// Once in binary 1-Wire mode, send 0x01 to get the current mode
+// version string. The Bus Pirate responds '1W0x', where x is the raw
+// 1-Wire protocol version (currently 1). Get the version string at
+// any time by sending 0x01 again.
+//
+// 00000010 - 1-Wire reset
+// Send a 1-Wire reset. Responds 0x01.
+//
+// 00000100 - Read byte
+// Reads a byte from the bus, returns the byte.
+//
+// 00001000 - ROM search macro (0xF0)
+// 00001001 - ALARM search macro (0xEC)
+// Search macros are special 1-Wire procedures that determine device
+// addresses. The command returns 0x01, and then each 8-byte 1-Wire
+// address located. Data ends with 8 bytes of 0xff.
+//
+// 0001xxxx - Bulk 1-Wire write, send 1-16 bytes (0=1byte!)
+// Bulk write transfers a packet of xxxx+1 bytes to the 1-Wire
+// bus. Up to 16 data bytes can be sent at once. Note that 0000
+// indicates 1 byte because there's no reason to send 0. BP replies
+// 0x01 to each byte.
+//
+// 0100wxyz - Configure peripherals w=power, x=pullups, y=AUX, z=CS
+// Enable (1) and disable (0) Bus Pirate peripherals and pins. Bit w
+// enables the power supplies, bit x toggles the on-board pull-up
+// resistors, y sets the state of the auxiliary pin, and z sets the
+// chip select pin. Features not present in a specific hardware
+// version are ignored. Bus Pirate responds 0x01 on success.
+//
+// Note: CS pin always follows the current HiZ pin configuration. AUX
+// is always a normal pin output (0=GND, 1=3.3volts).
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    ExitToBBIO,
+    OneWireVSN,
+    Reset,
+    ReadByte,
+    SearchROM,
+    SearchAlarm,
+    BulkWrite(Vec<u8>),
+    Configure(bool, bool, bool, bool)
+}
+
+use self::Message::*;
+impl Message {
+    /// The bytes to send, or an `InvalidInput` error when the message
+    /// can't be encoded.
+    pub fn send(&self) -> io::Result<Vec<u8>> {
+        Ok(match *self {
+            ExitToBBIO => vec![0b00000000],
+            OneWireVSN => vec![0b00000001],
+            Reset => vec![0b00000010],
+            ReadByte => vec![0b00000100],
+            SearchROM => vec![0b00001000],
+            SearchAlarm => vec![0b00001001],
+            BulkWrite(ref bytes) => return protocol::bulk(0b0001_0000, bytes),
+            Configure(power, pullups, aux, cs) =>
+                protocol::configure(power, pullups, aux, cs)
+        })
+    }
+
+    pub fn expect(&self) -> Option<Vec<u8>> {
+        match *self {
+            ExitToBBIO  => Some(vec![b'B', b'B', b'I', b'O', b'1']),
+            OneWireVSN  => Some(vec![b'1', b'W', b'0', b'1']),
+            Reset => Some(vec![0b00000001]),
+            SearchROM => Some(vec![0b00000001]),
+            SearchAlarm => Some(vec![0b00000001]),
+            BulkWrite(ref bytes) => Some(vec![0b00000001; bytes.len() + 1]),
+            Configure(_,_,_,_) => Some(vec![0b00000001]),
+            _ => None
+        }
+    }
+}
+
+impl Command for Message {
+    fn send(&self) -> io::Result<Vec<u8>> {
+        Message::send(self)
+    }
+
+    fn expect(&self) -> Option<Vec<u8>> {
+        Message::expect(self)
+    }
+}
@@ -1,20 +1,24 @@
-use serial::{SerialPort, SystemPort, Error};
+use serial::{SystemPort, Error};
 use serial::core::Result;
 use serial;
 
 const BBIO_RESP_V1: [u8; 5] = [b'B', b'B', b'I', b'O', b'1'];
 
-pub struct BusPirate {
-    port: SystemPort
+pub struct BusPirate<T: Transport = SystemPort> {
+    port: T
 }
 
-use std::io::{Read, Write, ErrorKind};
+use std::io::ErrorKind;
+use std::result;
+use failure;
 use std::time::{Instant, Duration};
 
 use super::bbio::{BBIOConn, BinModeVSN};
+use super::info::PirateInfo;
+use super::transport::Transport;
 
-impl BusPirate {
-    pub fn new(port: SystemPort) -> Self {
+impl<T: Transport> BusPirate<T> {
+    pub fn new(port: T) -> Self {
         Self { port: port }
     }
 
@@ -54,53 +58,296 @@ impl BusPirate {
            .join("\n"))
     }
 
-    pub fn enter_bio_mode(self) -> Result<BBIOConn> {
-        let mut port = self.port;
-        // Try to escape any prompt we're at.
-        try!(write!(port, "\n\n\n\n\n\n\n\n\n\n#\n"));
-
-        // Flush read
-        let mut buffer: Vec<u8> = Vec::new();
-        match port.read_to_end(&mut buffer) {
-            Ok(_) => (),
-            Err(ref e) if e.kind() == ErrorKind::TimedOut => (),
-            Err(e) => return Err(e.into())
-        }
+    /// `read_vsn`, parsed.
+    pub fn read_info(&mut self) -> result::Result<PirateInfo, failure::Error> {
+        let banner = try!(self.read_vsn());
+        PirateInfo::parse(&banner)
+    }
 
-        let original_timeout = port.timeout();
-        try!(port.set_timeout(Duration::from_millis(20)));
+    pub fn enter_bio_mode(self) -> Result<BBIOConn<T>> {
+        self.resync().map(|(conn, _)| conn)
+    }
 
-        for _try in 1..40 {
-            try!(port.write_all(&[0x00; 1]));
-            let mut vsn_vec: [u8; 5] = [0; 5];
-            match port.read_exact(&mut vsn_vec) {
-                Err(ref e) if e.kind() == ErrorKind::TimedOut => continue,
-                Err(e) => {
-                    try!(port.set_timeout(original_timeout));
-                    return Err(e.into())
-                }
-                Ok(()) => {
-                    if vsn_vec == BBIO_RESP_V1 {
-                        try!(port.set_timeout(original_timeout));
-                        return Ok(BBIOConn::new(port, BinModeVSN::One))
-                    }
-                    try!(port.set_timeout(original_timeout));
-                    return Err(Error::new(serial::ErrorKind::InvalidInput,
-                                          format!("Got {:?} while entering binmode",
-                                                  vsn_vec)));
-                }
+    /// Get the Pirate into raw bitbang mode from wherever it was left:
+    /// the terminal, another binary mode, a sniffer or partway through
+    /// a command. The 0x01 version query asks which mode it's in, then
+    /// 0x00s go out until "BBIO1" comes back. Attempts where the 0x00s
+    /// got nothing escape any terminal prompt or menu before trying
+    /// again, and every attempt waits longer for replies. Returns the
+    /// bitbang connection and the state the Pirate was found in.
+    ///
+    /// Only a Pirate found at the terminal is reset to read its
+    /// version banner into `BBIOConn::info`: coming from the terminal
+    /// starts bitbang mode from scratch anyway, while a reset from a
+    /// binary mode would switch off the power supplies, pull-ups, pins
+    /// and PWM. Without the version every version dependent command is
+    /// refused, use `BBIOConn::read_info` to read it regardless.
+    pub fn resync(self) -> Result<(BBIOConn<T>, PirateState)> {
+        let mut port = self.port;
+        let original_timeout = port.timeout();
+        let mut timeout = RESYNC_TIMEOUT;
+        let mut escape = false;
+        let mut res = Err(Error::new(serial::ErrorKind::InvalidInput,
+                                     "couldn't enter binary IO mode"));
+        for _attempt in 0..RESYNC_ATTEMPTS {
+            if escape {
+                // The terminal ignores 0x00s in its menus.
+                try!(write!(port, "\n\n\n\n\n\n\n\n\n\n#\n"));
+            }
+            res = try_resync(&mut port, timeout, original_timeout);
+            match res {
+                Ok(Some(_)) => break,
+                Ok(None) => escape = true,
+                Err(_) => escape = false
             }
+            timeout *= 2;
         }
         try!(port.set_timeout(original_timeout));
-        Err(Error::new(serial::ErrorKind::InvalidInput,
-                       "couldn't enter binary IO mode"))
+        let (state, banner) = match try!(res) {
+            Some(found) => found,
+            None => return Err(Error::new(serial::ErrorKind::InvalidInput,
+                                          "no BBIO1 in reply to 0x00s"))
+        };
+        let mut conn = BBIOConn::new(port, BinModeVSN::One);
+        if let Some(banner) = banner {
+            conn.info = Some(try!(parse_banner(&banner)));
+        }
+        Ok((conn, state))
+    }
+}
+
+/// Where `resync` found the Pirate, going by its answers to the 0x01
+/// version query and the 0x00s after it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PirateState {
+    /// The user terminal, which only switches after a run of 0x00s.
+    Terminal,
+    /// Binary SPI mode or raw bitbang mode. Bitbang mode answers the
+    /// query by switching to SPI, and nothing safe to send beforehand
+    /// tells the two apart.
+    SpiOrBitbang,
+    I2C,
+    Uart,
+    OneWire,
+    RawWire,
+    /// A binary mode that doesn't answer the query with a version
+    /// string. OpenOCD JTAG mode takes it as the start of a two byte
+    /// command and leaves on the second 0x00.
+    Binary,
+    /// The I2C or SPI sniffer, which the query stopped.
+    I2CSniffer,
+    SpiSniffer,
+    /// Partway through a command, which took the query and 0x00s as
+    /// its data before answering.
+    MidCommand
+}
+
+impl PirateState {
+    // The protocol mode a version string belongs to.
+    fn from_version(reply: &[u8]) -> Option<Self> {
+        let modes: [(&[u8], PirateState); 5] = [(b"SPI1", PirateState::SpiOrBitbang),
+                                                (b"I2C1", PirateState::I2C),
+                                                (b"ART1", PirateState::Uart),
+                                                (b"1W01", PirateState::OneWire),
+                                                (b"RAW1", PirateState::RawWire)];
+        modes.iter()
+            .find(|&&(vsn, _)| reply == vsn)
+            .map(|&(_, state)| state)
+    }
+}
+
+// Replies are waited for this long on the first attempt, doubling
+// every attempt after.
+const RESYNC_TIMEOUT: Duration = Duration::from_millis(20);
+const RESYNC_ATTEMPTS: usize = 4;
+// Stop draining after this much, in case a sniffer or voltage stream
+// is still going; the next byte sent stops those anyway.
+const DRAIN_LIMIT: usize = 4096;
+// The terminal switches to bitbang mode on the 20th 0x00 in a row.
+const TERMINAL_ZEROS: usize = 20;
+// 0x00s sent one at a time: the terminal wants 20, and a bulk
+// command can be waiting for up to 16 bytes.
+const SINGLE_ZEROS: usize = 25;
+// The longest a command can be waiting for data: a write then read
+// with its 4 length bytes and 4096 bytes to write still to come.
+const PENDING_ZEROS: usize = 4 + 4096;
+// Those go out in chunks this big, with a check for a reply in
+// between so not too many land in bitbang mode at once.
+const ZERO_CHUNK: usize = 16;
+// A write then read can answer with 4096 bytes, and every 0x00 after
+// it with "BBIO1".
+const ZEROS_DRAIN_LIMIT: usize = 4096 + 1 + (SINGLE_ZEROS + ZERO_CHUNK) * 5;
+
+// One attempt at getting to bitbang mode. None if the 0x00s got no
+// "BBIO1" at all, otherwise the state the Pirate was found in and,
+// when that was the terminal, the reset banner.
+fn try_resync<T: Transport>(port: &mut T, timeout: Duration, banner_timeout: Duration)
+                            -> Result<Option<(PirateState, Option<Vec<u8>>)>> {
+    try!(port.set_timeout(timeout));
+    let mut junk = Vec::new();
+    try!(read_available(port, &mut junk, DRAIN_LIMIT));
+
+    let queried = try!(query_mode(port));
+    let state = match (queried, try!(send_zeros(port))) {
+        (_, None) => return Ok(None),
+        (Some(state), _) => state,
+        (None, Some(state)) => state
+    };
+
+    let mut banner = None;
+    if state == PirateState::Terminal {
+        try!(read_available(port, &mut junk, DRAIN_LIMIT));
+        banner = Some(try!(reset_for_banner(port, timeout, banner_timeout)));
+    }
+    try!(realign(port));
+    Ok(Some((state, banner)))
+}
+
+// Send the 0x01 version query. An idle protocol mode answers with its
+// version string, which a command waiting for data never sends, so
+// only that is trusted. A bare 0x01 back is a sniffer stopping or the
+// terminal echoing it; asking again tells them apart.
+fn query_mode<T: Transport>(port: &mut T) -> Result<Option<PirateState>> {
+    let mut reply = Vec::new();
+    try!(port.write_all(&[0x01]));
+    try!(read_available(port, &mut reply, DRAIN_LIMIT));
+    if let Some(state) = PirateState::from_version(&reply) {
+        return Ok(Some(state));
+    }
+    if reply != [0x01] {
+        return Ok(None);
+    }
+    reply.clear();
+    try!(port.write_all(&[0x01]));
+    try!(read_available(port, &mut reply, DRAIN_LIMIT));
+    Ok(match PirateState::from_version(&reply) {
+        Some(PirateState::I2C) => Some(PirateState::I2CSniffer),
+        Some(PirateState::SpiOrBitbang) => Some(PirateState::SpiSniffer),
+        Some(_) => None,
+        None if reply == [0x01] => Some(PirateState::Terminal),
+        None => None
+    })
+}
+
+// Reset from bitbang mode with 0x0F, which answers 0x01 and prints the
+// version banner on the way back to the terminal, then get back to
+// bitbang mode. Returns the banner.
+fn reset_for_banner<T: Transport>(port: &mut T, timeout: Duration, banner_timeout: Duration)
+                                  -> Result<Vec<u8>> {
+    try!(port.write_all(&[0x0F]));
+    try!(port.set_timeout(banner_timeout));
+    let mut banner = Vec::new();
+    let res = read_available(port, &mut banner, DRAIN_LIMIT);
+    try!(port.set_timeout(timeout));
+    try!(res);
+    if banner.first() != Some(&0x01) {
+        return Err(Error::new(serial::ErrorKind::InvalidInput,
+                              format!("Got {:?} in reply to reset", banner)));
+    }
+    if try!(send_zeros(port)).is_none() {
+        return Err(Error::new(serial::ErrorKind::InvalidInput,
+                              "couldn't enter binary IO mode after reset"));
+    }
+    banner.remove(0);
+    Ok(banner)
+}
+
+// An unreadable banner is an error rather than a Pirate that can do
+// everything.
+fn parse_banner(banner: &[u8]) -> Result<PirateInfo> {
+    PirateInfo::parse(&String::from_utf8_lossy(banner))
+        .map_err(|e| Error::new(serial::ErrorKind::InvalidInput, e.to_string()))
+}
+
+// Extra "BBIO1"s may still be on their way; make sure the next reply
+// lines up.
+fn realign<T: Transport>(port: &mut T) -> Result<()> {
+    let mut junk = Vec::new();
+    try!(read_available(port, &mut junk, DRAIN_LIMIT));
+    try!(port.write_all(&[0x00]));
+    let mut vsn = [0; 5];
+    try!(port.read_exact(&mut vsn));
+    if vsn != BBIO_RESP_V1 {
+        return Err(Error::new(serial::ErrorKind::InvalidInput,
+                              format!("Got {:?} while entering binmode", vsn)));
+    }
+    Ok(())
+}
+
+/// Reset a Pirate in bitbang mode to read its version banner, and
+/// come back to bitbang mode.
+pub(crate) fn read_info_with_reset<T: Transport>(port: &mut T) -> Result<PirateInfo> {
+    let original_timeout = port.timeout();
+    let res = reset_for_banner(port, RESYNC_TIMEOUT, original_timeout)
+        .and_then(|banner| realign(port).map(|_| banner));
+    try!(port.set_timeout(original_timeout));
+    parse_banner(&try!(res))
+}
+
+// 0x00 leaves every binary mode, stops the sniffers and switches the
+// terminal to bitbang mode after 20 of them. A command waiting for
+// data takes them as data first. Returns how the Pirate got to
+// "BBIO1", or None if it never did.
+fn send_zeros<T: Transport>(port: &mut T) -> Result<Option<PirateState>> {
+    let mut reply = Vec::new();
+    for zero in 1..SINGLE_ZEROS + 1 {
+        try!(port.write_all(&[0x00]));
+        try!(read_available(port, &mut reply, ZEROS_DRAIN_LIMIT));
+        if reply.ends_with(&BBIO_RESP_V1) {
+            let before = &reply[..reply.len() - BBIO_RESP_V1.len()];
+            return Ok(Some(match (zero, before) {
+                (1, []) | (2, []) => PirateState::Binary,
+                (zero, []) if zero >= TERMINAL_ZEROS => PirateState::Terminal,
+                _ => PirateState::MidCommand
+            }));
+        }
+    }
+
+    // Nothing yet, so feed a pending command until it answers, then
+    // go back to single 0x00s for the exit.
+    let mut sent = 0;
+    while sent < PENDING_ZEROS {
+        let before = reply.len();
+        try!(port.write_all(&[0x00; ZERO_CHUNK]));
+        sent += ZERO_CHUNK;
+        try!(read_available(port, &mut reply, ZEROS_DRAIN_LIMIT));
+        if reply.len() > before {
+            break;
+        }
+    }
+    let mut zeros = 0;
+    while !reply.ends_with(&BBIO_RESP_V1) {
+        if zeros == SINGLE_ZEROS {
+            return Ok(None);
+        }
+        reply.clear();
+        try!(port.write_all(&[0x00]));
+        try!(read_available(port, &mut reply, ZEROS_DRAIN_LIMIT));
+        zeros += 1;
+    }
+    Ok(Some(PirateState::MidCommand))
+}
+
+// Read whatever arrives before the port times out, up to `limit`
+// bytes in all.
+fn read_available<T: Transport>(port: &mut T, buf: &mut Vec<u8>, limit: usize) -> Result<()> {
+    let mut chunk = [0; 64];
+    while buf.len() < limit {
+        match port.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => buf.extend(&chunk[..n]),
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into())
+        }
     }
+    Ok(())
 }
 
 use std::fmt;
 use serial::unix::TTYPort;
 use std::os::unix::io::AsRawFd;
-impl fmt::Debug for BusPirate {
+impl fmt::Debug for BusPirate<TTYPort> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let port: &TTYPort = &self.port;
         write!(f, "BusPirate {{ port: {} }}", port.as_raw_fd())
@@ -0,0 +1,123 @@
+use std::fmt::Debug;
+use std::io::{self, ErrorKind};
+use std::result::Result;
+
+use failure::Error;
+
+use super::transport::Transport;
+
+/// A binary mode command: its encoding, and the reply that
+/// acknowledges it if that's fixed.
+pub trait Command: Debug + Clone + Send + Sync + 'static {
+    fn send(&self) -> io::Result<Vec<u8>>;
+    fn expect(&self) -> Option<Vec<u8>>;
+}
+
+#[derive(Debug, Fail)]
+#[fail(display="sent: {:?} expected {:?}, received {:?}",
+       sent, expected, received)]
+pub struct InvalidReply<M: Command> {
+    pub sent: M,
+    pub expected: Vec<u8>,
+    pub received: Vec<u8>
+}
+
+/// Send `msg` and check it's answered with its fixed reply.
+pub fn call<T: Transport, M: Command>(port: &mut T, msg: &M) -> Result<Vec<u8>, Error> {
+    let good_reply = match msg.expect() {
+        Some(reply) => reply,
+        None => return Err(io::Error::new(ErrorKind::InvalidInput,
+                                          format!("{:?} has no fixed reply", msg)).into())
+    };
+    port.write_all(&msg.send()?)?;
+    let mut reply = vec![0; good_reply.len()];
+    port.read_exact(&mut reply)?;
+    if reply != good_reply {
+        return Err(InvalidReply { sent: msg.clone(),
+                                  expected: good_reply,
+                                  received: reply }.into());
+    }
+    Ok(reply)
+}
+
+/// The 0100wxyz configure peripherals command, the same in every
+/// protocol mode.
+pub fn configure(power: bool, pullups: bool, aux: bool, cs: bool) -> Vec<u8> {
+    let mut cmd = 0b0100_0000;
+    if power { cmd |= 0b1000; }
+    if pullups { cmd |= 0b0100; }
+    if aux { cmd |= 0b0010; }
+    if cs { cmd |= 0b0001; }
+    vec![cmd]
+}
+
+/// A bulk command, `cmd` with the count of 1-16 bytes minus one in
+/// the low nibble, followed by the bytes.
+pub fn bulk(cmd: u8, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = vec![cmd | (check_count(bytes.len(), 16)? - 1)];
+    buf.extend(bytes);
+    Ok(buf)
+}
+
+/// Check `count` fits a 1-`max` count field, which goes to the
+/// Pirate as `count - 1`.
+pub fn check_count(count: usize, max: usize) -> io::Result<u8> {
+    if count == 0 || count > max {
+        return Err(io::Error::new(ErrorKind::InvalidInput,
+                                  format!("count of {} out of range (1-{})",
+                                          count, max)));
+    }
+    Ok(count as u8)
+}
+
+/// Read one byte of a stream that ends by going quiet: `None` once
+/// the read times out.
+pub fn next_byte<T: Transport>(port: &mut T) -> Option<io::Result<u8>> {
+    let mut byte = [0; 1];
+    loop {
+        match port.read_exact(&mut byte) {
+            Ok(()) => return Some(Ok(byte[0])),
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => return None,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Some(Err(e))
+        }
+    }
+}
+
+/// Stop a stream with `exit`, then skip whatever was still in
+/// flight up to the byte `is_ack` picks out as the acknowledgement.
+pub fn exit_stream<T, F>(port: &mut T, exit: io::Result<Vec<u8>>, mut is_ack: F)
+    where T: Transport, F: FnMut(u8) -> bool
+{
+    if exit.and_then(|exit| port.write_all(&exit)).is_err() {
+        return;
+    }
+    while let Some(Ok(byte)) = next_byte(port) {
+        if is_ack(byte) {
+            break;
+        }
+    }
+}
+
+/// The settings `configure` applies in the modes with a speed and a
+/// config byte.
+pub struct BusSettings<S, C> {
+    pub(crate) speed: S,
+    pub(crate) config: C,
+    pub(crate) power: bool,
+    pub(crate) pullups: bool,
+    pub(crate) aux: bool,
+    pub(crate) cs: bool
+}
+
+impl<S, C> BusSettings<S, C> {
+    pub fn new(speed: S, config: C,
+               power: bool, pullups: bool, aux: bool, cs: bool) -> Self {
+        Self { speed: speed,
+               config: config,
+               power: power,
+               pullups: pullups,
+               aux: aux,
+               cs: cs }
+    }
+}
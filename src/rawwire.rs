@@ -0,0 +1,430 @@
+use serial::SystemPort;
+use std::str::FromStr;
+use std::result::Result;
+use std::io;
+
+use failure::Error;
+
+use super::info::{self, Capabilities, PirateInfo};
+use super::protocol::{self, Command, InvalidReply};
+use super::transport::Transport;
+
+pub struct RawWireConn<T: Transport = SystemPort> {
+    port: T,
+    info: Option<PirateInfo>
+}
+
+pub type BusSettings = protocol::BusSettings<Speed, Config>;
+
+#[derive(Debug, Fail)]
+enum CallError {
+    #[fail(display="can't send {} {} in one {} command (1-{})",
+           len, unit, command, max)]
+    InvalidLength { command: &'static str, unit: &'static str,
+                    len: usize, max: usize }
+}
+
+impl<T: Transport> RawWireConn<T> {
+    pub fn new(port: T) -> Self {
+        Self::with_info(port, None)
+    }
+
+    /// A connection that knows which Pirate it's talking to.
+    pub fn with_info(port: T, info: Option<PirateInfo>) -> Self {
+        Self { port: port, info: info }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        info::capabilities(self.info.as_ref())
+    }
+
+    pub fn test(&mut self) -> Result<(), Error> {
+        self.call(&Message::RawVSN)?;
+        Ok(())
+    }
+
+    fn call(&mut self, msg: &Message) -> Result<Vec<u8>, Error> {
+        protocol::call(&mut self.port, msg)
+    }
+
+    // Send a command whose reply is data rather than an ack.
+    fn query(&mut self, msg: &Message, len: usize) -> Result<Vec<u8>, Error> {
+        self.port.write_all(&msg.send()?)?;
+        let mut reply = vec![0; len];
+        self.port.read_exact(&mut reply)?;
+        Ok(reply)
+    }
+
+    pub fn configure(&mut self, settings: &BusSettings) -> Result<(), Error> {
+        self.call(&Message::SetSpeed(settings.speed))?;
+        self.call(&Message::SetConfig(settings.config))?;
+        self.call(&Message::Configure(settings.power,
+                                      settings.pullups,
+                                      settings.aux,
+                                      settings.cs))?;
+        Ok(())
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) -> Result<(), Error> {
+        self.call(&Message::SetSpeed(speed))?;
+        Ok(())
+    }
+
+    pub fn set_config(&mut self, config: Config) -> Result<(), Error> {
+        self.call(&Message::SetConfig(config))?;
+        Ok(())
+    }
+
+    pub fn set_peripherals(&mut self, power: bool, pullups: bool,
+                           aux: bool, cs: bool) -> Result<(), Error> {
+        self.call(&Message::Configure(power, pullups, aux, cs))?;
+        Ok(())
+    }
+
+    /// Send an I2C style start condition.
+    pub fn start_bit(&mut self) -> Result<(), Error> {
+        self.call(&Message::StartBit)?;
+        Ok(())
+    }
+
+    /// Send an I2C style stop condition.
+    pub fn stop_bit(&mut self) -> Result<(), Error> {
+        self.call(&Message::StopBit)?;
+        Ok(())
+    }
+
+    pub fn cs_low(&mut self) -> Result<(), Error> {
+        self.call(&Message::ChipSelect(false))?;
+        Ok(())
+    }
+
+    pub fn cs_high(&mut self) -> Result<(), Error> {
+        self.call(&Message::ChipSelect(true))?;
+        Ok(())
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, Error> {
+        Ok(self.query(&Message::ReadByte, 1)?[0])
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, Error> {
+        Ok(self.query(&Message::ReadBit, 1)?[0] != 0)
+    }
+
+    /// Sample the data input pin without clocking the bus.
+    pub fn peek(&mut self) -> Result<bool, Error> {
+        Ok(self.query(&Message::Peek, 1)?[0] != 0)
+    }
+
+    pub fn clock_tick(&mut self) -> Result<(), Error> {
+        self.call(&Message::ClockTick)?;
+        Ok(())
+    }
+
+    pub fn clock_low(&mut self) -> Result<(), Error> {
+        self.call(&Message::Clock(false))?;
+        Ok(())
+    }
+
+    pub fn clock_high(&mut self) -> Result<(), Error> {
+        self.call(&Message::Clock(true))?;
+        Ok(())
+    }
+
+    pub fn data_low(&mut self) -> Result<(), Error> {
+        self.call(&Message::Data(false))?;
+        Ok(())
+    }
+
+    pub fn data_high(&mut self) -> Result<(), Error> {
+        self.call(&Message::Data(true))?;
+        Ok(())
+    }
+
+    /// Write 1-16 bytes. Returns what the Pirate sent back for each
+    /// byte: the byte read from the bus in 3-wire mode, just 0x01 in
+    /// 2-wire mode.
+    pub fn bulk_write(&mut self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        if bytes.is_empty() || bytes.len() > 16 {
+            return Err(CallError::InvalidLength { command: "bulk write",
+                                                  unit: "bytes",
+                                                  len: bytes.len(),
+                                                  max: 16 }.into());
+        }
+        let msg = Message::BulkWrite(bytes.to_vec());
+        let mut reply = self.query(&msg, bytes.len() + 1)?;
+        if reply[0] != 0x01 {
+            return Err(InvalidReply { sent: msg,
+                                      expected: vec![0x01],
+                                      received: reply }.into());
+        }
+        reply.remove(0);
+        Ok(reply)
+    }
+
+    /// Pulse the clock 1-16 times.
+    pub fn bulk_clock_ticks(&mut self, ticks: usize) -> Result<(), Error> {
+        if ticks == 0 || ticks > 16 {
+            return Err(CallError::InvalidLength { command: "bulk clock",
+                                                  unit: "ticks",
+                                                  len: ticks,
+                                                  max: 16 }.into());
+        }
+        self.call(&Message::BulkClockTicks(ticks as u8))?;
+        Ok(())
+    }
+
+    /// Write the first 1-8 bits of `byte`, in the configured bit
+    /// order. Firmware before 4.5 doesn't have this.
+    pub fn bulk_bits(&mut self, byte: u8, bits: usize) -> Result<(), Error> {
+        info::require(self.info.as_ref(), Capabilities::BULK_BITS, "bulk bits")?;
+        if bits == 0 || bits > 8 {
+            return Err(CallError::InvalidLength { command: "bulk bits",
+                                                  unit: "bits",
+                                                  len: bits,
+                                                  max: 8 }.into());
+        }
+        self.call(&Message::BulkBits(byte, bits as u8))?;
+        Ok(())
+    }
+}
+
+impl<T: Transport> Drop for RawWireConn<T> {
+    fn drop(&mut self) {
+        let _ = self.call(&Message::Configure(false,false,false,false));
+        let _ = self.call(&Message::ExitToBBIO);
+    }
+}
+
+// 00000000 - Exit to bitbang mode, responds "BBIOx"
+// This command resets the Bus Pirate into raw bitbang mode from the
+// user terminal. It also resets to raw bitbang mode from raw-wire
+// mode, or any other protocol mode. This command always returns a
+// five byte bitbang version string "BBIOx", where x is the current
+// bitbang protocol version (currently 1).
+//
+// 00000001 - Display mode version string, responds "RAWx"
+// Once in raw-wire mode, send 0x01 to get the current mode version
+// string. The Bus Pirate responds 'RAWx', where x is the raw-wire
+// protocol version (currently 1). Get the version string at any time
+// by sending 0x01 again.
+//
+// 0000001x - I2C-style start (0) / stop (1) bit
+// Send an I2C start or stop bit. Responds 0x01. Useful for I2C-like
+// 2-wire protocols, or building a custom implementation of I2C using
+// the raw-wire library.
+//
+// 0000010x - CS low (0) / high (1)
+// Toggle the Bus Pirate chip select pin, follows HiZ configuration
+// setting. CS high is pin output at 3.3volts, or HiZ. CS low is pin
+// output at ground. Bus Pirate responds 0x01.
+//
+// 00000110 - Read byte
+// Reads a byte from the bus, returns the byte. Writes 0xff to bus in
+// 3-wire mode.
+//
+// 00000111 - Read bit
+// Read a single bit from the bus, returns the bit value.
+//
+// 00001000 - Peek at input pin
+// Reads the state of the bus data input pin without sending a clock
+// tick.
+//
+// 00001001 - Clock tick
+// Sends one clock tick (low->high->low). Responds 0x01.
+//
+// 0000101x - Clock low (0) / high (1)
+// Set clock signal low or high. Responds 0x01.
+//
+// 0000110x - Data low (0) / high (1)
+// Set data signal low or high. Responds 0x01.
+//
+// 0001xxxx - Bulk transfer, send 1-16 bytes (0=1byte!)
+// Bulk write transfers a packet of xxxx+1 bytes to the bus. Up to 16
+// data bytes can be sent at once. Note that 0000 indicates 1 byte
+// because there's no reason to send 0. BP replies 0x01 to each byte
+// in 2wire mode, returns the bus read in 3wire mode.
+//
+// 0010xxxx - Bulk clock ticks, send 1-16 ticks
+// Create bulk clock ticks on the bus. Note that 0000 indicates 1 clock
+// tick because there's no reason to send 0. BP replies 0x01.
+//
+// 0011xxxx - Bulk bits, send 1-8 bits of the next byte (0=1bit!)
+// (added in v4.5)
+// Bulk bits sends xxxx+1 bits of the next byte to the bus. Up to 8
+// bits can be sent at once. Note that 0000 indicates 1 bit because
+// there's no reason to send 0. BP replies 0x01.
+//
+// 0100wxyz - Configure peripherals w=power, x=pullups, y=AUX, z=CS
+// Enable (1) and disable (0) Bus Pirate peripherals and pins. Bit w
+// enables the power supplies, bit x toggles the on-board pull-up
+// resistors, y sets the state of the auxiliary pin, and z sets the
+// chip select pin. Features not present in a specific hardware
+// version are ignored. Bus Pirate responds 0x01 on success.
+//
+// Note: CS pin always follows the current HiZ pin configuration. AUX
+// is always a normal pin output (0=GND, 1=3.3volts).
+//
+// 011000xx - Set bus speed, 3=~400kHz, 2=~100kHz, 1=~50kHz, 0=~5kHz
+// The lower bits of the speed command determine the raw-wire bus
+// speed. Startup default is high-speed. Bus Pirate responds 0x01.
+//
+// 1000wxyz - Config, w=HiZ/3.3v, x=2/3wire, y=msb/lsb, z=not used
+// Configure the raw-wire settings. w sets the pin output type
+// HiZ(0)/3.3v(1). x toggles between 2-wire (0) and 3-wire (1)
+// mode. y sets the bit order, MSB first (0) or LSB first (1). The
+// Bus Pirate responds 0x01 on success.
+//
+// Default raw-wire startup condition is 000=HiZ, 2-wire, MSB first.
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    ExitToBBIO,
+    RawVSN,
+    StartBit,
+    StopBit,
+    ChipSelect(bool),
+    ReadByte,
+    ReadBit,
+    Peek,
+    ClockTick,
+    Clock(bool),
+    Data(bool),
+    BulkWrite(Vec<u8>),
+    BulkClockTicks(u8),
+    BulkBits(u8, u8),
+    Configure(bool, bool, bool, bool),
+    SetSpeed(Speed),
+    SetConfig(Config)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Speed {
+    Hz400000 = 0b11,
+    Hz100000 = 0b10,
+    Hz50000  = 0b01,
+    Hz5000   = 0b00
+}
+
+impl FromStr for Speed {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "400000" => Ok(Speed::Hz400000),
+            "100000" => Ok(Speed::Hz100000),
+            "50000"  => Ok(Speed::Hz50000),
+            "5000"   => Ok(Speed::Hz5000),
+            "400k"   => Ok(Speed::Hz400000),
+            "100k"   => Ok(Speed::Hz100000),
+            "50k"    => Ok(Speed::Hz50000),
+            "5k"     => Ok(Speed::Hz5000),
+            _        => Err("Invalid raw-wire bus speed")
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Output {
+    HiZ  = 0b0000,
+    V3_3 = 0b1000
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Wires {
+    Two   = 0b0000,
+    Three = 0b0100
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitOrder {
+    MsbFirst = 0b0000,
+    LsbFirst = 0b0010
+}
+
+/// The 1000wxyz raw-wire config bits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub output: Output,
+    pub wires: Wires,
+    pub bit_order: BitOrder
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { output: Output::HiZ,
+               wires: Wires::Two,
+               bit_order: BitOrder::MsbFirst }
+    }
+}
+
+impl Config {
+    pub fn bits(&self) -> u8 {
+        self.output as u8 | self.wires as u8 | self.bit_order as u8
+    }
+}
+
+use self::Message::*;
+impl Message {
+    /// The bytes to send, or an `InvalidInput` error when the message
+    /// can't be encoded.
+    pub fn send(&self) -> io::Result<Vec<u8>> {
+        Ok(match *self {
+            ExitToBBIO => vec![0b00000000],
+            RawVSN => vec![0b00000001],
+            StartBit => vec![0b00000010],
+            StopBit => vec![0b00000011],
+            ChipSelect(high) => vec![0b0000_0100 | high as u8],
+            ReadByte => vec![0b00000110],
+            ReadBit => vec![0b00000111],
+            Peek => vec![0b00001000],
+            ClockTick => vec![0b00001001],
+            Clock(high) => vec![0b0000_1010 | high as u8],
+            Data(high) => vec![0b0000_1100 | high as u8],
+            BulkWrite(ref bytes) => return protocol::bulk(0b0001_0000, bytes),
+            BulkClockTicks(ticks) => {
+                vec![0b0010_0000 | (protocol::check_count(ticks as usize, 16)? - 1)]
+            },
+            BulkBits(byte, bits) => {
+                vec![0b0011_0000 | (protocol::check_count(bits as usize, 8)? - 1), byte]
+            },
+            Configure(power, pullups, aux, cs) =>
+                protocol::configure(power, pullups, aux, cs),
+            SetSpeed(speed) => {
+                vec![0b0110_0000 | speed as u8]
+            },
+            SetConfig(config) => {
+                vec![0b1000_0000 | config.bits()]
+            }
+        })
+    }
+
+    pub fn expect(&self) -> Option<Vec<u8>> {
+        match *self {
+            ExitToBBIO  => Some(vec![b'B', b'B', b'I', b'O', b'1']),
+            RawVSN  => Some(vec![b'R', b'A', b'W', b'1']),
+            StartBit => Some(vec![0b00000001]),
+            StopBit => Some(vec![0b00000001]),
+            ChipSelect(_) => Some(vec![0b00000001]),
+            ClockTick => Some(vec![0b00000001]),
+            Clock(_) => Some(vec![0b00000001]),
+            Data(_) => Some(vec![0b00000001]),
+            BulkClockTicks(_) => Some(vec![0b00000001]),
+            BulkBits(_, _) => Some(vec![0b00000001]),
+            Configure(_,_,_,_) => Some(vec![0b00000001]),
+            SetSpeed(_) => Some(vec![0b00000001]),
+            SetConfig(_) => Some(vec![0b00000001]),
+            _ => None
+        }
+    }
+}
+
+impl Command for Message {
+    fn send(&self) -> io::Result<Vec<u8>> {
+        Message::send(self)
+    }
+
+    fn expect(&self) -> Option<Vec<u8>> {
+        Message::expect(self)
+    }
+}
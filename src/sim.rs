@@ -0,0 +1,1079 @@
+use serial::core::Result;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write, ErrorKind};
+use std::mem;
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::bbio::Pins;
+use super::i2c::Addr;
+use super::jtag::TapState;
+use super::onewire::RomId;
+use super::transport::Transport;
+
+const DEFAULT_BANNER: &str = "Bus Pirate v4\r\n\
+                                      Firmware v6.2-beta1 r1981 \r\n\
+                                      DEVID:0x1019 REVID:0x0004 (24FJ256GB106 UNK)\r\n\
+                                      http://dangerousprototypes.com";
+
+/// The protocol mode the simulated Pirate is currently in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    Terminal,
+    BBIO,
+    I2C,
+    SPI,
+    SpiSniffer,
+    I2CSniffer,
+    UART,
+    UartBridge,
+    OneWire,
+    RawWire,
+    ContinuousVoltage,
+    SelfTest,
+    Jtag
+}
+
+/// A virtual device hanging off the simulated I2C bus.
+pub trait I2CDevice {
+    /// Called when the device is addressed after a (repeated) start
+    /// bit. `read` is the R/W bit of the address byte.
+    fn select(&mut self, _read: bool) {}
+    /// A data byte written by the master. Return true to ACK it.
+    fn write(&mut self, byte: u8) -> bool;
+    /// The next byte to hand the master.
+    fn read(&mut self) -> u8;
+    /// Called on a stop bit while the device is selected.
+    fn stop(&mut self) {}
+}
+
+impl<D: I2CDevice> I2CDevice for Rc<RefCell<D>> {
+    fn select(&mut self, read: bool) { self.borrow_mut().select(read) }
+    fn write(&mut self, byte: u8) -> bool { self.borrow_mut().write(byte) }
+    fn read(&mut self) -> u8 { self.borrow_mut().read() }
+    fn stop(&mut self) { self.borrow_mut().stop() }
+}
+
+/// A virtual device on the simulated SPI bus, selected by CS.
+pub trait SpiDevice {
+    /// Called when CS goes low.
+    fn select(&mut self) {}
+    /// Shift one byte in from MOSI, returning the byte for MISO.
+    fn transfer(&mut self, mosi: u8) -> u8;
+    /// Called when CS goes high.
+    fn deselect(&mut self) {}
+}
+
+impl<D: SpiDevice> SpiDevice for Rc<RefCell<D>> {
+    fn select(&mut self) { self.borrow_mut().select() }
+    fn transfer(&mut self, mosi: u8) -> u8 { self.borrow_mut().transfer(mosi) }
+    fn deselect(&mut self) { self.borrow_mut().deselect() }
+}
+
+/// A virtual device on the simulated 1-Wire bus. The simulator
+/// handles the ROM layer (MATCH ROM, SKIP ROM, READ ROM and the search
+/// macros); the device sees the function commands and data that
+/// follow once it is selected.
+pub trait OneWireDevice {
+    fn rom(&self) -> RomId;
+    fn alarm(&self) -> bool { false }
+    fn reset(&mut self) {}
+    fn write(&mut self, byte: u8);
+    fn read(&mut self) -> u8;
+}
+
+impl<D: OneWireDevice> OneWireDevice for Rc<RefCell<D>> {
+    fn rom(&self) -> RomId { self.borrow().rom() }
+    fn alarm(&self) -> bool { self.borrow().alarm() }
+    fn reset(&mut self) { self.borrow_mut().reset() }
+    fn write(&mut self, byte: u8) { self.borrow_mut().write(byte) }
+    fn read(&mut self) -> u8 { self.borrow_mut().read() }
+}
+
+/// A register-pointer memory: the first `addr_bytes` bytes written
+/// after selection set the pointer (high byte first), the rest are
+/// stored there. Reads return bytes from the pointer onwards. This is
+/// how most I2C EEPROMs and sensor register files behave.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    pub data: Vec<u8>,
+    addr_bytes: usize,
+    pointer: usize,
+    addr_seen: usize
+}
+
+impl Memory {
+    pub fn new(size: usize, addr_bytes: usize) -> Self {
+        Self { data: vec![0xFF; size],
+               addr_bytes: addr_bytes,
+               pointer: 0,
+               addr_seen: 0 }
+    }
+}
+
+impl I2CDevice for Memory {
+    fn select(&mut self, _read: bool) {
+        self.addr_seen = 0;
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        if self.addr_seen < self.addr_bytes {
+            if self.addr_seen == 0 {
+                self.pointer = 0;
+            }
+            self.pointer = (self.pointer << 8 | byte as usize) % self.data.len();
+            self.addr_seen += 1;
+        } else {
+            let len = self.data.len();
+            self.data[self.pointer] = byte;
+            self.pointer = (self.pointer + 1) % len;
+        }
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let byte = self.data[self.pointer];
+        self.pointer = (self.pointer + 1) % self.data.len();
+        byte
+    }
+}
+
+struct I2CBus {
+    devices: BTreeMap<Addr, Box<dyn I2CDevice>>,
+    selected: Option<Addr>,
+    addressing: bool
+}
+
+impl I2CBus {
+    fn start(&mut self) {
+        self.addressing = true;
+    }
+
+    fn stop(&mut self) {
+        if let Some(dev) = self.selected.and_then(|a| self.devices.get_mut(&a)) {
+            dev.stop();
+        }
+        self.selected = None;
+        self.addressing = false;
+    }
+
+    // Returns true if the byte was ACKed.
+    fn write(&mut self, byte: u8) -> bool {
+        if self.addressing {
+            self.addressing = false;
+            let addr = byte >> 1;
+            match self.devices.get_mut(&addr) {
+                Some(dev) => {
+                    dev.select(byte & 1 == 1);
+                    self.selected = Some(addr);
+                    true
+                }
+                None => {
+                    self.selected = None;
+                    false
+                }
+            }
+        } else {
+            match self.selected.and_then(|a| self.devices.get_mut(&a)) {
+                Some(dev) => dev.write(byte),
+                None => false
+            }
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        match self.selected.and_then(|a| self.devices.get_mut(&a)) {
+            Some(dev) => dev.read(),
+            // Nobody driving the bus, the pull-ups win.
+            None => 0xFF
+        }
+    }
+}
+
+struct SpiBus {
+    device: Option<Box<dyn SpiDevice>>,
+    selected: bool
+}
+
+impl SpiBus {
+    fn cs(&mut self, high: bool) {
+        if high == self.selected {
+            if let Some(ref mut dev) = self.device {
+                if high { dev.deselect() } else { dev.select() }
+            }
+        }
+        self.selected = !high;
+    }
+
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        match self.device {
+            Some(ref mut dev) if self.selected => dev.transfer(mosi),
+            _ => 0xFF
+        }
+    }
+}
+
+enum OneWireState {
+    // Just reset, the next byte is a ROM command.
+    RomCommand,
+    Matching(Vec<u8>),
+    ReadingRom(VecDeque<u8>),
+    Selected(Vec<usize>),
+    Idle
+}
+
+struct OneWireBus {
+    devices: Vec<Box<dyn OneWireDevice>>,
+    state: OneWireState
+}
+
+impl OneWireBus {
+    fn reset(&mut self) {
+        for dev in &mut self.devices {
+            dev.reset();
+        }
+        self.state = OneWireState::RomCommand;
+    }
+
+    fn write(&mut self, byte: u8) {
+        let state = mem::replace(&mut self.state, OneWireState::Idle);
+        self.state = match state {
+            OneWireState::RomCommand => match byte {
+                0x55 => OneWireState::Matching(Vec::new()),
+                0xCC => OneWireState::Selected((0..self.devices.len()).collect()),
+                0x33 => {
+                    // Every device answers at once, wired-AND style.
+                    let rom = self.devices.iter()
+                        .fold(!0u64, |acc, dev| acc & dev.rom().0);
+                    OneWireState::ReadingRom(RomId(rom).bytes().iter().cloned().collect())
+                }
+                _ => OneWireState::Idle
+            },
+            OneWireState::Matching(mut rom) => {
+                rom.push(byte);
+                if rom.len() < 8 {
+                    OneWireState::Matching(rom)
+                } else {
+                    let selected = self.devices.iter().enumerate()
+                        .filter(|&(_, dev)| dev.rom().bytes()[..] == rom[..])
+                        .map(|(i, _)| i)
+                        .collect();
+                    OneWireState::Selected(selected)
+                }
+            }
+            OneWireState::Selected(selected) => {
+                for &i in &selected {
+                    self.devices[i].write(byte);
+                }
+                OneWireState::Selected(selected)
+            }
+            other => other
+        };
+    }
+
+    fn read(&mut self) -> u8 {
+        match self.state {
+            OneWireState::ReadingRom(ref mut rom) => rom.pop_front().unwrap_or(0xFF),
+            OneWireState::Selected(ref selected) => {
+                let devices = &mut self.devices;
+                selected.iter().fold(0xFF, |acc, &i| acc & devices[i].read())
+            }
+            _ => 0xFF
+        }
+    }
+
+    fn search(&mut self, alarm: bool) -> Vec<RomId> {
+        self.reset();
+        self.state = OneWireState::Idle;
+        let mut roms = self.devices.iter()
+            .filter(|dev| !alarm || dev.alarm())
+            .map(|dev| dev.rom())
+            .collect::<Vec<RomId>>();
+        roms.sort();
+        roms
+    }
+}
+
+struct JtagTap {
+    idcode: Option<u32>,
+    ir_len: usize,
+    bypass: bool
+}
+
+// A scan chain of TAPs that only know IDCODE and BYPASS: an all ones
+// instruction selects BYPASS, anything else IDCODE (if the TAP has
+// one). Device 0 is nearest TDO.
+struct JtagChain {
+    state: TapState,
+    taps: Vec<JtagTap>,
+    shift: VecDeque<bool>
+}
+
+impl JtagChain {
+    fn clock(&mut self, tms: bool, tdi: bool) -> bool {
+        let mut tdo = false;
+        match self.state {
+            TapState::CaptureIR => {
+                // IR captures must end in 01.
+                self.shift = self.taps.iter()
+                    .flat_map(|tap| (0..tap.ir_len).map(|i| i == 0))
+                    .collect();
+            }
+            TapState::CaptureDR => {
+                self.shift = self.taps.iter()
+                    .flat_map(|tap| match tap.idcode {
+                        Some(id) if !tap.bypass =>
+                            (0..32).map(|i| id & (1 << i) != 0).collect(),
+                        _ => vec![false]
+                    })
+                    .collect();
+            }
+            TapState::ShiftIR | TapState::ShiftDR => {
+                self.shift.push_back(tdi);
+                tdo = self.shift.pop_front().unwrap_or(tdi);
+            }
+            _ => ()
+        }
+        self.state = self.state.next(tms);
+        match self.state {
+            TapState::TestLogicReset => for tap in &mut self.taps {
+                tap.bypass = tap.idcode.is_none();
+            },
+            TapState::UpdateIR => {
+                let mut bits = self.shift.iter();
+                for tap in &mut self.taps {
+                    let ones = bits.by_ref().take(tap.ir_len).filter(|&&b| b).count();
+                    tap.bypass = tap.idcode.is_none() || ones == tap.ir_len;
+                }
+            }
+            _ => ()
+        }
+        tdo
+    }
+}
+
+#[derive(Default)]
+struct Uart {
+    sent: Vec<u8>,
+    received: Vec<u8>,
+    echo: bool
+}
+
+/// A software Bus Pirate that can stand in for the serial port.
+///
+/// Replies are queued as commands are written; reading with nothing
+/// queued fails with `ErrorKind::TimedOut` just like an idle serial
+/// port. Emulated modes:
+///
+/// - the terminal: `#` reset and the 20 zero entry to binary mode
+/// - bitbang: pins, PWM, voltage probe, frequency and self-test
+/// - I2C, against devices attached with `add_i2c_device`
+/// - SPI, against the device set with `set_spi_device`
+/// - UART: `uart_sent` collects what the Pirate transmits and
+///   `uart_receive` plays the target's side
+/// - 1-Wire, against devices attached with `add_onewire_device`
+/// - raw-wire, with nothing on the bus
+/// - OpenOCD JTAG, against the chain built with `add_jtag_device`
+pub struct Simulator {
+    mode: Mode,
+    banner: String,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+    timeout: Duration,
+    zeros: usize,
+    line: String,
+    i2c: I2CBus,
+    spi: SpiBus,
+    uart: Uart,
+    onewire: OneWireBus,
+    three_wire: bool,
+    pin_inputs: Pins,
+    pin_outputs: Pins,
+    pin_levels: Pins,
+    pwm: Option<(u8, u16, u16)>,
+    probe_adc: u16,
+    aux_frequency: u32,
+    self_test_errors: u8,
+    jtag: JtagChain,
+    vextern: bool
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self { mode: Mode::Terminal,
+               banner: DEFAULT_BANNER.to_string(),
+               input: Vec::new(),
+               output: VecDeque::new(),
+               timeout: Duration::from_millis(0),
+               zeros: 0,
+               line: String::new(),
+               i2c: I2CBus { devices: BTreeMap::new(),
+                             selected: None,
+                             addressing: false },
+               spi: SpiBus { device: None, selected: false },
+               uart: Uart::default(),
+               onewire: OneWireBus { devices: Vec::new(),
+                                     state: OneWireState::Idle },
+               three_wire: false,
+               pin_inputs: Pins::IO,
+               pin_outputs: Pins::empty(),
+               pin_levels: Pins::empty(),
+               pwm: None,
+               probe_adc: 0,
+               aux_frequency: 0,
+               self_test_errors: 0,
+               jtag: JtagChain { state: TapState::TestLogicReset,
+                                 taps: Vec::new(),
+                                 shift: VecDeque::new() },
+               vextern: false }
+    }
+
+    /// Replace the version banner printed after a terminal reset.
+    /// Lines are separated by "\r\n" as on the real hardware.
+    pub fn with_banner(mut self, banner: &str) -> Self {
+        self.banner = banner.to_string();
+        self
+    }
+
+    pub fn add_i2c_device(&mut self, addr: Addr, device: Box<dyn I2CDevice>) {
+        self.i2c.devices.insert(addr, device);
+    }
+
+    pub fn set_spi_device(&mut self, device: Box<dyn SpiDevice>) {
+        self.spi.device = Some(device);
+    }
+
+    pub fn add_onewire_device(&mut self, device: Box<dyn OneWireDevice>) {
+        self.onewire.devices.push(device);
+    }
+
+    /// Add a TAP with an `ir_len` bit instruction register to the end
+    /// of the JTAG chain furthest from TDO. Without an IDCODE it comes
+    /// out of reset in BYPASS.
+    pub fn add_jtag_device(&mut self, idcode: Option<u32>, ir_len: usize) {
+        self.jtag.taps.push(JtagTap { idcode: idcode,
+                                      ir_len: ir_len,
+                                      bypass: idcode.is_none() });
+    }
+
+    /// Drive the bitbang pins from outside: members of `levels` read
+    /// high while they are configured as inputs.
+    pub fn set_pin_levels(&mut self, levels: Pins) {
+        self.pin_levels = levels;
+    }
+
+    /// The bitbang pins configured as inputs.
+    pub fn pin_inputs(&self) -> Pins {
+        self.pin_inputs
+    }
+
+    /// The bitbang output, power and pull-up bits last set.
+    pub fn pin_outputs(&self) -> Pins {
+        self.pin_outputs
+    }
+
+    /// The PWM (prescaler bits, duty register, period register) last
+    /// set up, or None if PWM is off.
+    pub fn pwm(&self) -> Option<(u8, u16, u16)> {
+        self.pwm
+    }
+
+    /// Set the voltage the probe measures.
+    pub fn set_probe_voltage(&mut self, volts: f64) {
+        self.probe_adc = (volts / 6.6 * 1024.0).round().clamp(0.0, 1023.0) as u16;
+    }
+
+    /// Set the frequency the counter sees on AUX, in Hz.
+    pub fn set_aux_frequency(&mut self, hz: u32) {
+        self.aux_frequency = hz;
+    }
+
+    /// Put a voltage on VEXTERN, making the pull-up supply select
+    /// refuse to connect a rail.
+    pub fn set_vextern(&mut self, present: bool) {
+        self.vextern = present;
+    }
+
+    /// Set the error count the self-tests report.
+    pub fn set_self_test_errors(&mut self, errors: u8) {
+        self.self_test_errors = errors;
+    }
+
+    fn probe(&mut self) {
+        let adc = self.probe_adc;
+        self.reply(&[(adc >> 8) as u8, adc as u8]);
+    }
+
+    fn pin_state(&self) -> Pins {
+        (self.pin_outputs - self.pin_inputs) | (self.pin_levels & self.pin_inputs)
+    }
+
+    /// Everything the Pirate has transmitted on its UART so far.
+    pub fn uart_sent(&self) -> &[u8] {
+        &self.uart.sent
+    }
+
+    /// Bytes arriving from the target on the Pirate's UART RX. They
+    /// are held until RX echo (or the bridge) passes them on.
+    pub fn uart_receive(&mut self, bytes: &[u8]) {
+        self.uart.received.extend_from_slice(bytes);
+        self.forward_uart();
+    }
+
+    fn forward_uart(&mut self) {
+        if self.uart.echo || self.mode == Mode::UartBridge {
+            let received = mem::take(&mut self.uart.received);
+            self.reply(&received);
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn reply(&mut self, bytes: &[u8]) {
+        self.output.extend(bytes);
+    }
+
+    fn process(&mut self) {
+        let input = mem::take(&mut self.input);
+        let mut pos = 0;
+        while pos < input.len() {
+            let rest = &input[pos..];
+            let used = match self.mode {
+                Mode::Terminal => self.terminal(rest),
+                Mode::BBIO => self.bbio(rest),
+                Mode::I2C => self.i2c(rest),
+                Mode::SPI => self.spi(rest),
+                Mode::UART => self.uart(rest),
+                Mode::OneWire => self.onewire(rest),
+                Mode::UartBridge => {
+                    self.uart.sent.extend_from_slice(rest);
+                    Some(rest.len())
+                }
+                Mode::I2CSniffer => {
+                    // Nothing to sniff, any byte stops the sniffer.
+                    self.reply(&[0x01]);
+                    self.mode = Mode::I2C;
+                    Some(1)
+                }
+                Mode::SpiSniffer => {
+                    // Nothing to sniff, any byte stops the sniffer.
+                    self.reply(&[0x01]);
+                    self.mode = Mode::SPI;
+                    Some(1)
+                }
+                Mode::RawWire => self.rawwire(rest),
+                Mode::Jtag => self.jtag(rest),
+                Mode::SelfTest => {
+                    // Input is echoed until 0xFF ends the test.
+                    if rest[0] == 0xFF {
+                        self.reply(&[0x01]);
+                        self.mode = Mode::BBIO;
+                    } else {
+                        self.reply(&rest[..1]);
+                    }
+                    Some(1)
+                }
+                Mode::ContinuousVoltage => {
+                    // Any byte stops the stream.
+                    self.mode = Mode::BBIO;
+                    Some(1)
+                }
+            };
+            match used {
+                Some(n) => pos += n,
+                None => break
+            }
+        }
+        self.input = input[pos..].to_vec();
+    }
+
+    fn reset(&mut self) {
+        let banner = format!("RESET\r\n{}\r\nHiZ>", self.banner);
+        self.reply(banner.as_bytes());
+        // A hardware reset leaves every pin HiZ and the PWM off.
+        self.pin_inputs = Pins::IO;
+        self.pin_outputs = Pins::empty();
+        self.pwm = None;
+        self.mode = Mode::Terminal;
+        self.zeros = 0;
+        self.line.clear();
+    }
+
+    fn enter_bbio(&mut self) {
+        self.reply(b"BBIO1");
+        self.mode = Mode::BBIO;
+        self.pin_inputs = Pins::IO;
+        self.pin_outputs = Pins::empty();
+    }
+
+    fn terminal(&mut self, input: &[u8]) -> Option<usize> {
+        let byte = input[0];
+        if byte == 0x00 {
+            self.zeros += 1;
+            if self.zeros >= 20 {
+                self.enter_bbio();
+            }
+            return Some(1);
+        }
+        self.zeros = 0;
+        match byte {
+            b'\r' | b'\n' => {
+                let line = mem::take(&mut self.line);
+                self.reply(b"\r\n");
+                if line.trim() == "#" {
+                    self.reset();
+                } else {
+                    self.reply(b"HiZ>");
+                }
+            }
+            _ => {
+                self.line.push(byte as char);
+                self.reply(&[byte]);
+            }
+        }
+        Some(1)
+    }
+
+    fn bbio(&mut self, input: &[u8]) -> Option<usize> {
+        match input[0] {
+            0b00000000 => self.enter_bbio(),
+            0b00000001 => {
+                self.reply(b"SPI1");
+                self.mode = Mode::SPI;
+            }
+            0b00000010 => {
+                self.reply(b"I2C1");
+                self.mode = Mode::I2C;
+            }
+            0b00000011 => {
+                self.reply(b"ART1");
+                self.uart.echo = false;
+                self.mode = Mode::UART;
+            }
+            0b00000100 => {
+                self.reply(b"1W01");
+                self.mode = Mode::OneWire;
+            }
+            0b00000101 => {
+                self.reply(b"RAW1");
+                self.three_wire = false;
+                self.mode = Mode::RawWire;
+            }
+            0b00000110 => {
+                self.reply(b"OCD1");
+                self.mode = Mode::Jtag;
+            }
+            0b00001111 => {
+                self.reply(&[0x01]);
+                self.reset();
+            }
+            0b00010000 | 0b00010001 => {
+                let errors = self.self_test_errors;
+                self.reply(&[errors]);
+                self.mode = Mode::SelfTest;
+            }
+            0b00010010 => {
+                if input.len() < 6 {
+                    return None;
+                }
+                self.pwm = Some((input[1],
+                                 (input[2] as u16) << 8 | input[3] as u16,
+                                 (input[4] as u16) << 8 | input[5] as u16));
+                self.reply(&[0x01]);
+                return Some(6);
+            }
+            0b00010011 => {
+                self.pwm = None;
+                self.reply(&[0x01]);
+            }
+            0b00010100 => self.probe(),
+            0b00010101 => self.mode = Mode::ContinuousVoltage,
+            0b00010110 => {
+                let hz = self.aux_frequency;
+                self.reply(&[(hz >> 24) as u8, (hz >> 16) as u8, (hz >> 8) as u8, hz as u8]);
+            }
+            0b0100_0000..=0b0101_1111 => {
+                self.pin_inputs = Pins::from_bits_truncate(input[0]) & Pins::IO;
+                let state = self.pin_state().bits();
+                self.reply(&[state]);
+            }
+            0b1000_0000..=0b1111_1111 => {
+                self.pin_outputs = Pins::from_bits_truncate(input[0]);
+                let state = self.pin_state().bits();
+                self.reply(&[state]);
+            }
+            // Reserved and unknown commands answer 0x00, like the firmware.
+            _ => self.reply(&[0x00])
+        }
+        Some(1)
+    }
+
+    fn jtag(&mut self, input: &[u8]) -> Option<usize> {
+        let needed = match input[0] {
+            0b00000001 | 0b00000111 => 2,
+            0b00000010 | 0b00000101 => 3,
+            _ => 1
+        };
+        if input.len() < needed {
+            return None;
+        }
+        match input[0] {
+            0b00000000 => self.enter_bbio(),
+            // Port mode and features have nothing to act on.
+            0b00000001 | 0b00000010 => (),
+            0b00000011 => {
+                // Probe, Vpullup, 3.3V and 5V.
+                let adcs = [self.probe_adc, 0, 512, 776];
+                self.reply(&[0b00000011, 8]);
+                for adc in &adcs {
+                    self.reply(&[(*adc >> 8) as u8, *adc as u8]);
+                }
+            }
+            0b00000101 => {
+                let bits = (input[1] as usize) << 8 | input[2] as usize;
+                let bytes = bits.div_ceil(8);
+                if input.len() < 3 + 2 * bytes {
+                    return None;
+                }
+                let mut tdo = vec![0; bytes];
+                for i in 0..bits {
+                    let pair = 3 + 2 * (i / 8);
+                    let bit = 1 << (i % 8);
+                    if self.jtag.clock(input[pair + 1] & bit != 0,
+                                       input[pair] & bit != 0) {
+                        tdo[i / 8] |= bit;
+                    }
+                }
+                self.reply(&input[..3]);
+                self.reply(&tdo);
+                return Some(3 + 2 * bytes);
+            }
+            0b00000111 => {
+                // There's no baud rate to change, just wait for the
+                // 0xAA 0x55 sent at the new one.
+                if input.len() < 4 {
+                    return None;
+                }
+                self.reply(&input[..2]);
+                return Some(4);
+            }
+            _ => ()
+        }
+        Some(needed)
+    }
+
+    fn i2c(&mut self, input: &[u8]) -> Option<usize> {
+        let cmd = input[0];
+        match cmd {
+            0b00000000 => {
+                self.i2c.stop();
+                self.enter_bbio();
+            }
+            0b00000001 => self.reply(b"I2C1"),
+            0b00000010 => {
+                self.i2c.start();
+                self.reply(&[0x01]);
+            }
+            0b00000011 => {
+                self.i2c.stop();
+                self.reply(&[0x01]);
+            }
+            0b00000100 => {
+                let byte = self.i2c.read();
+                self.reply(&[byte]);
+            }
+            0b00000110 | 0b00000111 => self.reply(&[0x01]),
+            0b00001000 => return self.i2c_write_then_read(input),
+            0b00001111 => self.mode = Mode::I2CSniffer,
+            0b0001_0000..=0b0001_1111 => {
+                let len = (cmd & 0x0F) as usize + 1;
+                if input.len() < len + 1 {
+                    return None;
+                }
+                self.reply(&[0x01]);
+                for &byte in &input[1..len + 1] {
+                    let ack = self.i2c.write(byte);
+                    self.reply(&[if ack { 0x00 } else { 0x01 }]);
+                }
+                return Some(len + 1);
+            }
+            0b0101_0001..=0b0101_0011 if self.vextern => self.reply(&[0x00]),
+            0b0100_0000..=0b0100_1111 |
+            0b0101_0000..=0b0101_0011 |
+            0b0110_0000..=0b0110_0011 => self.reply(&[0x01]),
+            _ => self.reply(&[0x00])
+        }
+        Some(1)
+    }
+
+    fn uart(&mut self, input: &[u8]) -> Option<usize> {
+        let cmd = input[0];
+        match cmd {
+            0b00000000 => {
+                self.uart.echo = false;
+                self.enter_bbio();
+            }
+            0b00000001 => self.reply(b"ART1"),
+            0b00000010 => {
+                self.reply(&[0x01]);
+                self.uart.echo = true;
+                self.forward_uart();
+            }
+            0b00000011 => {
+                self.uart.echo = false;
+                self.reply(&[0x01]);
+            }
+            0b00000111 => {
+                if input.len() < 3 {
+                    return None;
+                }
+                self.reply(&[0x01, 0x01, 0x01]);
+                return Some(3);
+            }
+            0b00001111 => {
+                self.mode = Mode::UartBridge;
+                self.forward_uart();
+            }
+            0b0001_0000..=0b0001_1111 => {
+                let len = (cmd & 0x0F) as usize + 1;
+                if input.len() < len + 1 {
+                    return None;
+                }
+                self.uart.sent.extend_from_slice(&input[1..len + 1]);
+                self.reply(&vec![0x01; len + 1]);
+                return Some(len + 1);
+            }
+            0b0100_0000..=0b0100_1111 |
+            0b0101_0000..=0b0101_0011 |
+            0b0110_0000..=0b0110_1010 |
+            0b1000_0000..=0b1001_1111 => self.reply(&[0x01]),
+            _ => self.reply(&[0x00])
+        }
+        Some(1)
+    }
+
+    // Nothing is attached in raw-wire mode, the bus reads as pulled up.
+    fn rawwire(&mut self, input: &[u8]) -> Option<usize> {
+        let cmd = input[0];
+        match cmd {
+            0b00000000 => self.enter_bbio(),
+            0b00000001 => self.reply(b"RAW1"),
+            0b00000110 => self.reply(&[0xFF]),
+            0b00000111 | 0b00001000 => self.reply(&[0x01]),
+            0b0001_0000..=0b0001_1111 => {
+                let len = (cmd & 0x0F) as usize + 1;
+                if input.len() < len + 1 {
+                    return None;
+                }
+                let each = if self.three_wire { 0xFF } else { 0x01 };
+                self.reply(&[0x01]);
+                self.reply(&vec![each; len]);
+                return Some(len + 1);
+            }
+            0b0011_0000..=0b0011_0111 => {
+                if input.len() < 2 {
+                    return None;
+                }
+                self.reply(&[0x01]);
+                return Some(2);
+            }
+            0b1000_0000..=0b1000_1111 => {
+                self.three_wire = cmd & 0b0100 != 0;
+                self.reply(&[0x01]);
+            }
+            0b00000010..=0b00000101 |
+            0b00001001..=0b00001101 |
+            0b0010_0000..=0b0010_1111 |
+            0b0100_0000..=0b0101_0011 |
+            0b0110_0000..=0b0110_0011 => self.reply(&[0x01]),
+            _ => self.reply(&[0x00])
+        }
+        Some(1)
+    }
+
+    fn onewire(&mut self, input: &[u8]) -> Option<usize> {
+        let cmd = input[0];
+        match cmd {
+            0b00000000 => self.enter_bbio(),
+            0b00000001 => self.reply(b"1W01"),
+            0b00000010 => {
+                self.onewire.reset();
+                self.reply(&[0x01]);
+            }
+            0b00000100 => {
+                let byte = self.onewire.read();
+                self.reply(&[byte]);
+            }
+            0b00001000 | 0b00001001 => {
+                let roms = self.onewire.search(cmd == 0b00001001);
+                self.reply(&[0x01]);
+                for rom in roms {
+                    self.reply(&rom.bytes());
+                }
+                self.reply(&[0xFF; 8]);
+            }
+            0b0001_0000..=0b0001_1111 => {
+                let len = (cmd & 0x0F) as usize + 1;
+                if input.len() < len + 1 {
+                    return None;
+                }
+                for &byte in &input[1..len + 1] {
+                    self.onewire.write(byte);
+                }
+                self.reply(&vec![0x01; len + 1]);
+                return Some(len + 1);
+            }
+            // Peripherals and pull-up voltage.
+            0b0100_0000..=0b0101_0011 => self.reply(&[0x01]),
+            _ => self.reply(&[0x00])
+        }
+        Some(1)
+    }
+
+    fn spi(&mut self, input: &[u8]) -> Option<usize> {
+        let cmd = input[0];
+        match cmd {
+            0b00000000 => {
+                self.spi.cs(true);
+                self.enter_bbio();
+            }
+            0b00000001 => self.reply(b"SPI1"),
+            0b00000010 | 0b00000011 => {
+                self.spi.cs(cmd & 1 == 1);
+                self.reply(&[0x01]);
+            }
+            0b00000100 | 0b00000101 => return self.spi_write_then_read(input),
+            0b00001101 | 0b00001110 => {
+                self.reply(&[0x01]);
+                self.mode = Mode::SpiSniffer;
+            }
+            0b0001_0000..=0b0001_1111 => {
+                let len = (cmd & 0x0F) as usize + 1;
+                if input.len() < len + 1 {
+                    return None;
+                }
+                self.reply(&[0x01]);
+                for &byte in &input[1..len + 1] {
+                    let miso = self.spi.transfer(byte);
+                    self.reply(&[miso]);
+                }
+                return Some(len + 1);
+            }
+            0b0100_0000..=0b0100_1111 |
+            0b0101_0000..=0b0101_0011 |
+            0b0110_0000..=0b0110_0111 |
+            0b1000_0000..=0b1000_1111 => self.reply(&[0x01]),
+            _ => self.reply(&[0x00])
+        }
+        Some(1)
+    }
+
+    fn spi_write_then_read(&mut self, input: &[u8]) -> Option<usize> {
+        if input.len() < 5 {
+            return None;
+        }
+        let cs = input[0] == 0b00000100;
+        let write_len = (input[1] as usize) << 8 | input[2] as usize;
+        let read_len = (input[3] as usize) << 8 | input[4] as usize;
+        if write_len > 4096 || read_len > 4096 {
+            self.reply(&[0x00]);
+            return Some(5);
+        }
+        if input.len() < 5 + write_len {
+            return None;
+        }
+
+        if cs {
+            self.spi.cs(false);
+        }
+        for &byte in &input[5..5 + write_len] {
+            self.spi.transfer(byte);
+        }
+        let data = (0..read_len).map(|_| self.spi.transfer(0xFF)).collect::<Vec<u8>>();
+        if cs {
+            self.spi.cs(true);
+        }
+        self.reply(&[0x01]);
+        self.reply(&data);
+        Some(5 + write_len)
+    }
+
+    fn i2c_write_then_read(&mut self, input: &[u8]) -> Option<usize> {
+        if input.len() < 5 {
+            return None;
+        }
+        let write_len = (input[1] as usize) << 8 | input[2] as usize;
+        let read_len = (input[3] as usize) << 8 | input[4] as usize;
+        if write_len > 4096 || read_len > 4096 {
+            self.reply(&[0x00]);
+            return Some(5);
+        }
+        if input.len() < 5 + write_len {
+            return None;
+        }
+
+        self.i2c.start();
+        for &byte in &input[5..5 + write_len] {
+            if !self.i2c.write(byte) {
+                self.i2c.stop();
+                self.reply(&[0x00]);
+                return Some(5 + write_len);
+            }
+        }
+        let data = (0..read_len).map(|_| self.i2c.read()).collect::<Vec<u8>>();
+        self.i2c.stop();
+        self.reply(&[0x01]);
+        self.reply(&data);
+        Some(5 + write_len)
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Read for Simulator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.output.is_empty() && self.mode == Mode::ContinuousVoltage {
+            self.probe();
+        }
+        if self.output.is_empty() {
+            return Err(io::Error::new(ErrorKind::TimedOut,
+                                      "simulated Bus Pirate has nothing to send"));
+        }
+        let n = buf.len().min(self.output.len());
+        for (slot, byte) in buf.iter_mut().zip(self.output.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Simulator {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.input.extend_from_slice(buf);
+        self.process();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Simulator {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
@@ -0,0 +1,534 @@
+use serial::SystemPort;
+use std::str::FromStr;
+use std::result::Result;
+use std::io::{self, ErrorKind};
+
+use failure::Error;
+
+use super::info::{self, Capabilities, PirateInfo};
+use super::protocol::{self, Command, InvalidReply};
+use super::transport::Transport;
+
+pub struct SpiConn<T: Transport = SystemPort> {
+    port: T,
+    info: Option<PirateInfo>
+}
+
+pub type BusSettings = protocol::BusSettings<Speed, Config>;
+
+#[derive(Debug, Fail)]
+enum CallError {
+    #[fail(display="can't transfer {} bytes in one {} command (max {})",
+           len, command, max)]
+    InvalidLength { command: &'static str, len: usize, max: usize },
+    #[fail(display="write then read of {} bytes out, {} bytes in was refused",
+           write_len, read_len)]
+    WriteThenReadFailed { write_len: usize, read_len: usize }
+}
+
+impl<T: Transport> SpiConn<T> {
+    pub fn new(port: T) -> Self {
+        Self::with_info(port, None)
+    }
+
+    /// A connection that knows which Pirate it's talking to.
+    pub fn with_info(port: T, info: Option<PirateInfo>) -> Self {
+        Self { port: port, info: info }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        info::capabilities(self.info.as_ref())
+    }
+
+    pub fn test(&mut self) -> Result<(), Error> {
+        self.call(&Message::SpiVSN)?;
+        Ok(())
+    }
+
+    fn call(&mut self, msg: &Message) -> Result<Vec<u8>, Error> {
+        protocol::call(&mut self.port, msg)
+    }
+
+    pub fn configure(&mut self, settings: &BusSettings) -> Result<(), Error> {
+        self.call(&Message::SetSpeed(settings.speed))?;
+        self.call(&Message::SetConfig(settings.config))?;
+        self.call(&Message::Configure(settings.power,
+                                      settings.pullups,
+                                      settings.aux,
+                                      settings.cs))?;
+        Ok(())
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) -> Result<(), Error> {
+        self.call(&Message::SetSpeed(speed))?;
+        Ok(())
+    }
+
+    pub fn set_config(&mut self, config: Config) -> Result<(), Error> {
+        self.call(&Message::SetConfig(config))?;
+        Ok(())
+    }
+
+    pub fn set_peripherals(&mut self, power: bool, pullups: bool,
+                           aux: bool, cs: bool) -> Result<(), Error> {
+        self.call(&Message::Configure(power, pullups, aux, cs))?;
+        Ok(())
+    }
+
+    pub fn cs_low(&mut self) -> Result<(), Error> {
+        self.call(&Message::ChipSelect(false))?;
+        Ok(())
+    }
+
+    pub fn cs_high(&mut self) -> Result<(), Error> {
+        self.call(&Message::ChipSelect(true))?;
+        Ok(())
+    }
+
+    /// Clock 1-16 bytes out on MOSI, returning the bytes read from
+    /// MISO at the same time. CS is left alone.
+    pub fn bulk_transfer(&mut self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        if bytes.is_empty() || bytes.len() > 16 {
+            return Err(CallError::InvalidLength { command: "bulk transfer",
+                                                  len: bytes.len(),
+                                                  max: 16 }.into());
+        }
+        let msg = Message::BulkTransfer(bytes.to_vec());
+        self.port.write_all(&msg.send()?)?;
+        let mut reply = vec![0; bytes.len() + 1];
+        self.port.read_exact(&mut reply)?;
+        if reply[0] != 0x01 {
+            return Err(InvalidReply { sent: msg,
+                                      expected: vec![0x01],
+                                      received: reply }.into());
+        }
+        reply.remove(0);
+        Ok(reply)
+    }
+
+    /// Full duplex transfer of any length, split into bulk transfers
+    /// of up to 16 bytes.
+    pub fn transfer(&mut self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut read = Vec::with_capacity(bytes.len());
+        for chunk in bytes.chunks(16) {
+            read.extend(self.bulk_transfer(chunk)?);
+        }
+        Ok(read)
+    }
+
+    /// Assert CS, write `write`, read `read_len` bytes and release
+    /// CS, all buffered inside the Pirate. Both directions are limited
+    /// to 4096 bytes.
+    pub fn write_then_read(&mut self, write: &[u8], read_len: usize)
+                           -> Result<Vec<u8>, Error> {
+        self.buffered_write_then_read(true, write, read_len)
+    }
+
+    /// Same as `write_then_read` without touching CS.
+    pub fn write_then_read_no_cs(&mut self, write: &[u8], read_len: usize)
+                                 -> Result<Vec<u8>, Error> {
+        self.buffered_write_then_read(false, write, read_len)
+    }
+
+    fn buffered_write_then_read(&mut self, cs: bool, write: &[u8],
+                                read_len: usize) -> Result<Vec<u8>, Error> {
+        for &len in &[write.len(), read_len] {
+            if len > 4096 {
+                return Err(CallError::InvalidLength { command: "write then read",
+                                                      len: len,
+                                                      max: 4096 }.into());
+            }
+        }
+        let msg = Message::WriteThenRead(cs, write.to_vec(), read_len as u16);
+        self.port.write_all(&msg.send()?)?;
+        let mut status = [0; 1];
+        self.port.read_exact(&mut status)?;
+        if status[0] != 0x01 {
+            return Err(CallError::WriteThenReadFailed { write_len: write.len(),
+                                                        read_len: read_len }.into());
+        }
+        let mut reply = vec![0; read_len];
+        self.port.read_exact(&mut reply)?;
+        Ok(reply)
+    }
+
+    /// Start the hardware SPI sniffer. Sniffed traffic is read from
+    /// the returned `Sniffer`, which leaves sniffer mode when dropped.
+    pub fn sniff(&mut self, filter: SnifferFilter) -> Result<Sniffer<'_, T>, Error> {
+        self.call(&Message::StartSniffer(filter))?;
+        Ok(Sniffer { conn: self, decoder: Decoder::default() })
+    }
+}
+
+impl<T: Transport> Drop for SpiConn<T> {
+    fn drop(&mut self) {
+        let _ = self.call(&Message::Configure(false,false,false,false));
+        let _ = self.call(&Message::ExitToBBIO);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SnifferEvent {
+    CsEnable,
+    CsDisable,
+    Data { mosi: u8, miso: u8 }
+}
+
+pub struct Sniffer<'a, T: Transport + 'a> {
+    conn: &'a mut SpiConn<T>,
+    decoder: Decoder
+}
+
+#[derive(Default)]
+struct Decoder {
+    escaped: Vec<u8>,
+    in_escape: bool
+}
+
+impl Decoder {
+    // Data comes as '\' and then the MOSI and MISO bytes, so 0x01 can
+    // only be the exit ack when it isn't escaped.
+    fn decode(&mut self, byte: u8) -> Option<Result<SnifferEvent, u8>> {
+        if self.in_escape {
+            self.escaped.push(byte);
+            if self.escaped.len() < 2 {
+                return None;
+            }
+            self.in_escape = false;
+            let data = SnifferEvent::Data { mosi: self.escaped[0],
+                                            miso: self.escaped[1] };
+            self.escaped.clear();
+            return Some(Ok(data));
+        }
+        match byte {
+            b'[' => Some(Ok(SnifferEvent::CsEnable)),
+            b']' => Some(Ok(SnifferEvent::CsDisable)),
+            b'\\' => {
+                self.in_escape = true;
+                None
+            }
+            other => Some(Err(other))
+        }
+    }
+}
+
+/// Yields CS edges and MOSI/MISO byte pairs until nothing arrives
+/// within the port timeout. Iterating again picks up where the stream
+/// left off.
+impl<'a, T: Transport> Iterator for Sniffer<'a, T> {
+    type Item = Result<SnifferEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let byte = match protocol::next_byte(&mut self.conn.port)? {
+                Ok(byte) => byte,
+                Err(e) => return Some(Err(e.into()))
+            };
+            match self.decoder.decode(byte) {
+                None => continue,
+                Some(Ok(event)) => return Some(Ok(event)),
+                Some(Err(other)) =>
+                    return Some(Err(format_err!("unexpected byte {:#04x} from SPI sniffer",
+                                                other)))
+            }
+        }
+    }
+}
+
+impl<'a, T: Transport> Drop for Sniffer<'a, T> {
+    fn drop(&mut self) {
+        // Any byte stops the sniffer.
+        let decoder = &mut self.decoder;
+        protocol::exit_stream(&mut self.conn.port, Message::ExitSniffer.send(),
+                              |byte| decoder.decode(byte) == Some(Err(0x01)));
+    }
+}
+
+// 00000000 - Enter raw bitbang mode, reset SPI mode
+// This command resets the Bus Pirate into raw bitbang mode from raw
+// SPI mode, responds "BBIOx".
+//
+// 00000001 - Enter raw SPI mode, display version string
+// Once in raw bitbang mode, send 0x01 to enter raw SPI mode. The Bus
+// Pirate responds 'SPIx', where x is the raw SPI protocol version
+// (currently 1). Get the version string at any time by sending 0x01
+// again.
+//
+// 0000001x - CS high (1) or low (0)
+// Toggle the Bus Pirate chip select pin, follows HiZ
+// configuration setting. CS high is pin output at 3.3volts, or HiZ. CS
+// low is pin output at ground. Bus Pirate responds 0x01.
+//
+// 000011XX - Sniff SPI traffic when CS low(10)/all(01)
+// The SPI sniffer is implemented in hardware and should work up to
+// 10MHz. It follows the configuration settings you entered for SPI
+// mode. The sniffer can read all traffic, or filter by the state of
+// the CS pin.
+//
+// [/] - CS enable/disable
+// \xy - escape character (\) precedes two byte values X (MOSI pin)
+// and Y (MISO pin) (updated in v5.1)
+// Sniffed traffic is encoded according to the table above. The two
+// data bytes are escaped with the '\' character to help locate data
+// in the stream.
+//
+// Send the SPI sniffer command to start the sniffer, the Bus Pirate
+// responds 0x01 then sniffed data starts to flow. Send any byte to
+// exit. Bus Pirate responds 0x01 on exit. (0x01 reply location was
+// changed in v5.8)
+//
+// If the sniffer can't keep with the SPI data, the MODE LED turns off
+// and the sniff is aborted. (new in v5.1)
+//
+// The sniffer follows the output clock edge and output polarity
+// settings of the SPI mode, but not the input sample phase.
+//
+// 0001xxxx - Bulk SPI transfer, send/read 1-16 bytes (0=1byte!)
+// Bulk SPI allows direct byte reads and writes. The Bus Pirate
+// expects xxxx+1 data bytes. Up to 16 data bytes can be sent at
+// once, each returns a byte read from the SPI bus during the write.
+//
+// Note that 0000 indicates 1 byte because there's no reason to send
+// 0. BP replies 0x01 to the bulk SPI command, and returns the value
+// read from SPI after each data byte write.
+//
+// The way it goes together:
+// The upper 4 bit of the command byte are the bulk read command
+// (0001xxxx). xxxx = the number of bytes to read. 0000=1, 0001=2,
+// etc, up to 1111=16. If we want to read (0001) four bytes (0011=3=4
+// bytes) The command should be 00010011 (0001 0011 = 0x13). Send 0x13
+// then four data bytes. The Bus Pirate responds 0x01, then returns
+// one byte read from SPI for each data byte written.
+//
+// 0100wxyz - Configure peripherals w=power, x=pull-ups, y=AUX, z=CS
+// Enable (1) and disable (0) Bus Pirate peripherals and pins. Bit w
+// enables the power supplies, bit x toggles the on-board pull-up
+// resistors, y sets the state of the auxiliary pin, and z sets the
+// chip select pin. Features not present in a specific hardware
+// version are ignored. Bus Pirate responds 0x01 on success.
+//
+// Note: CS pin always follows the current HiZ pin configuration. AUX
+// is always a normal pin output (0=GND, 1=3.3volts).
+//
+// 01100xxx - SPI speed
+// 000=30kHz, 001=125kHz, 010=250kHz, 011=1MHz, 100=2MHz, 101=2.6MHz,
+// 110=4MHz, 111=8MHz
+// This command sets the SPI bus speed according to the values
+// shown. Default startup speed is 000 (30kHz).
+//
+// 1000wxyz - SPI config, w=HiZ/3.3v, x=CKP idle, y=CKE edge, z=SMP sample
+// This command configures the SPI settings. Options and start-up
+// defaults are the same as the user terminal SPI mode. w= pin output
+// HiZ(0)/3.3v(1), x=CKP clock idle phase (low=0), y=CKE clock edge
+// (active to idle=1), z=SMP sample time (middle=0). The Bus Pirate
+// responds 0x01 on success.
+//
+// Default raw SPI startup condition is 0010. HiZ mode configuration
+// applies to the SPI pins and the CS pin, but not the AUX pin. See
+// the PIC24FJ64GA002 datasheet and the SPI section of the PIC24
+// family manual for more about the SPI configuration settings.
+//
+// 00000100 - Write then read
+// This command was developed to help speed ROM programming with
+// Flashrom. It might be helpful for a lot of common SPI
+// operations. It enables chip select, writes 0-4096 bytes, reads
+// 0-4096 bytes, then disables chip select.
+//
+// All data for this command can be sent at once, and it will be
+// buffered in the Bus Pirate. The write and read operations happen
+// all at once, and the read data is buffered. At the end of the
+// operation, the read data is returned from the buffer. The goal is
+// to meet the stringent timing requirements of some ROM chips by
+// buffering everything instead of letting the serial port delay
+// things.
+//
+// Write then read command format
+// command (1byte) number of write bytes (2bytes) number of read bytes
+// (2bytes) bytes to write (0-4096bytes)
+// Return data format
+// success/0x01 (1byte) bytes read from SPI (0-4096bytes)
+//
+// First send the write then read command (00000100)
+// The next two bytes (High8/Low8) set the number of bytes to write
+// (0 to 4096)
+// The next two bytes (h/l) set the number of bytes to read (0 to
+// 4096)
+// If the number of bytes to read or write are out of bounds, the Bus
+// Pirate will return 0x00 now
+// Next, send the bytes to write. Bytes are buffered in the Bus
+// Pirate, there is no acknowledgment that a byte is received.
+// Now the Bus Pirate enables chip select, writes the bytes, reads
+// the bytes into its buffer, then disables chip select.
+// The Bus Pirate returns 0x01, success.
+// Finally, the buffered read bytes are returned.
+//
+// 00000101 - Write then read, no CS
+// Identical to the command above, but chip select is left alone.
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    ExitToBBIO,
+    SpiVSN,
+    ChipSelect(bool),
+    StartSniffer(SnifferFilter),
+    ExitSniffer,
+    BulkTransfer(Vec<u8>),
+    Configure(bool, bool, bool, bool),
+    SetSpeed(Speed),
+    SetConfig(Config),
+    WriteThenRead(bool, Vec<u8>, u16)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum SnifferFilter {
+    All   = 0b01,
+    CsLow = 0b10
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Speed {
+    Hz30000   = 0b000,
+    Hz125000  = 0b001,
+    Hz250000  = 0b010,
+    Hz1000000 = 0b011,
+    Hz2000000 = 0b100,
+    Hz2600000 = 0b101,
+    Hz4000000 = 0b110,
+    Hz8000000 = 0b111
+}
+
+impl FromStr for Speed {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "30000"   => Ok(Speed::Hz30000),
+            "125000"  => Ok(Speed::Hz125000),
+            "250000"  => Ok(Speed::Hz250000),
+            "1000000" => Ok(Speed::Hz1000000),
+            "2000000" => Ok(Speed::Hz2000000),
+            "2600000" => Ok(Speed::Hz2600000),
+            "4000000" => Ok(Speed::Hz4000000),
+            "8000000" => Ok(Speed::Hz8000000),
+            "30k"     => Ok(Speed::Hz30000),
+            "125k"    => Ok(Speed::Hz125000),
+            "250k"    => Ok(Speed::Hz250000),
+            "1M"      => Ok(Speed::Hz1000000),
+            "2M"      => Ok(Speed::Hz2000000),
+            "2.6M"    => Ok(Speed::Hz2600000),
+            "4M"      => Ok(Speed::Hz4000000),
+            "8M"      => Ok(Speed::Hz8000000),
+            _         => Err("Invalid spi bus speed")
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Output {
+    HiZ  = 0b0000,
+    V3_3 = 0b1000
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockIdle {
+    Low  = 0b0000,
+    High = 0b0100
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockEdge {
+    IdleToActive = 0b0000,
+    ActiveToIdle = 0b0010
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SamplePoint {
+    Middle = 0b0000,
+    End    = 0b0001
+}
+
+/// The 1000wxyz SPI config bits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub output: Output,
+    pub idle: ClockIdle,
+    pub edge: ClockEdge,
+    pub sample: SamplePoint
+}
+
+impl Default for Config {
+    // Same as the Pirate's own startup config, 0010.
+    fn default() -> Self {
+        Self { output: Output::HiZ,
+               idle: ClockIdle::Low,
+               edge: ClockEdge::ActiveToIdle,
+               sample: SamplePoint::Middle }
+    }
+}
+
+impl Config {
+    pub fn bits(&self) -> u8 {
+        self.output as u8 | self.idle as u8 | self.edge as u8 | self.sample as u8
+    }
+}
+
+use self::Message::*;
+impl Message {
+    /// The bytes to send, or an `InvalidInput` error when the message
+    /// can't be encoded.
+    pub fn send(&self) -> io::Result<Vec<u8>> {
+        Ok(match *self {
+            ExitToBBIO => vec![0b00000000],
+            SpiVSN => vec![0b00000001],
+            ChipSelect(high) => vec![0b0000_0010 | high as u8],
+            StartSniffer(filter) => vec![0b0000_1100 | filter as u8],
+            ExitSniffer => vec![0b00000000],
+            BulkTransfer(ref bytes) => return protocol::bulk(0b0001_0000, bytes),
+            Configure(power, pullups, aux, cs) =>
+                protocol::configure(power, pullups, aux, cs),
+            SetSpeed(speed) => {
+                vec![0b0110_0000 | speed as u8]
+            },
+            SetConfig(config) => {
+                vec![0b1000_0000 | config.bits()]
+            },
+            WriteThenRead(_, ref bytes, read_len) if bytes.len() > 4096 || read_len > 4096 =>
+                return Err(io::Error::new(ErrorKind::InvalidInput,
+                                          "write then read is limited to 4096 bytes")),
+            WriteThenRead(cs, ref bytes, read_len) => {
+                let cmd = if cs { 0b0000_0100 } else { 0b0000_0101 };
+                let write_len = bytes.len() as u16;
+                let mut buf: Vec<u8> = vec![cmd,
+                                            (write_len >> 8) as u8,
+                                            write_len as u8,
+                                            (read_len >> 8) as u8,
+                                            read_len as u8];
+                buf.extend(bytes);
+                buf
+            }
+        })
+    }
+
+    pub fn expect(&self) -> Option<Vec<u8>> {
+        match *self {
+            ExitToBBIO  => Some(vec![b'B', b'B', b'I', b'O', b'1']),
+            SpiVSN  => Some(vec![b'S', b'P', b'I', b'1']),
+            ChipSelect(_) => Some(vec![0b00000001]),
+            StartSniffer(_) => Some(vec![0b00000001]),
+            Configure(_,_,_,_) => Some(vec![0b00000001]),
+            SetSpeed(_) => Some(vec![0b00000001]),
+            SetConfig(_) => Some(vec![0b00000001]),
+            _ => None
+        }
+    }
+}
+
+impl Command for Message {
+    fn send(&self) -> io::Result<Vec<u8>> {
+        Message::send(self)
+    }
+
+    fn expect(&self) -> Option<Vec<u8>> {
+        Message::expect(self)
+    }
+}
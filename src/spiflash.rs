@@ -0,0 +1,485 @@
+use serial::SystemPort;
+use std::fmt;
+use std::result::Result;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+
+use super::spi::SpiConn;
+use super::transport::Transport;
+
+// The commands shared by (nearly) every 25-series SPI NOR flash.
+const WRITE_STATUS: u8 = 0x01;
+const PAGE_PROGRAM: u8 = 0x02;
+const READ_STATUS: u8 = 0x05;
+const WRITE_ENABLE: u8 = 0x06;
+const FAST_READ: u8 = 0x0B;
+const READ_SFDP: u8 = 0x5A;
+const READ_ID: u8 = 0x9F;
+const ENTER_4BYTE: u8 = 0xB7;
+const CHIP_ERASE: u8 = 0xC7;
+const EXIT_4BYTE: u8 = 0xE9;
+
+// The most one write then read can fetch.
+const MAX_READ: usize = 4096;
+
+// Datasheet maximums are a few ms for a page or status write and a
+// couple of seconds for a 64K block; big parts take minutes to erase
+// completely.
+const PROGRAM_TIMEOUT: Duration = Duration::from_millis(100);
+const ERASE_TIMEOUT: Duration = Duration::from_secs(5);
+const CHIP_ERASE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Manufacturer, memory type and capacity bytes from READ ID (0x9F).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct JedecId {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    pub capacity: u8
+}
+
+impl fmt::Display for JedecId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}", self.manufacturer, self.memory_type, self.capacity)
+    }
+}
+
+/// One of the erase commands a part supports.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EraseType {
+    pub size: usize,
+    pub opcode: u8
+}
+
+/// How a part is laid out, from its SFDP basic flash parameter table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Geometry {
+    /// Size in bytes.
+    pub size: usize,
+    /// Bytes one page program can write, programs wrap at the page end.
+    pub page_size: usize,
+    /// The supported erases, smallest first.
+    pub erase: Vec<EraseType>,
+    /// Address bytes sent with each command, 3 or 4.
+    pub addr_bytes: usize
+}
+
+impl Geometry {
+    /// Parse the basic flash parameter table (JESD216), given as its
+    /// little endian dwords.
+    pub fn from_bfpt(dwords: &[u32]) -> Result<Self, Error> {
+        if dwords.len() < 9 {
+            return Err(FlashError::InvalidSfdp {
+                reason: "basic flash parameter table is too short"
+            }.into());
+        }
+        // Densities up to 2Gbit are the bit count minus one, bigger
+        // ones are a power of two.
+        let density = dwords[1];
+        let bits = if density & 0x8000_0000 == 0 {
+            density as u64 + 1
+        } else {
+            match 1u64.checked_shl(density & 0x7FFF_FFFF) {
+                Some(bits) => bits,
+                None => return Err(FlashError::InvalidSfdp {
+                    reason: "density is out of range"
+                }.into())
+            }
+        };
+        let size = (bits / 8) as usize;
+
+        // Each erase type is a size exponent and an opcode, a zero
+        // exponent marks an unused slot.
+        let mut erase = Vec::new();
+        for e in dwords[7..9].iter().flat_map(|&dword| vec![dword, dword >> 16]) {
+            let exponent = e & 0xFF;
+            if exponent == 0 {
+                continue;
+            }
+            if exponent < 8 || exponent > 31 {
+                return Err(FlashError::InvalidSfdp {
+                    reason: "erase size is out of range"
+                }.into());
+            }
+            erase.push(EraseType { size: 1 << exponent, opcode: (e >> 8) as u8 });
+        }
+        // Fall back on the uniform 4K erase from the first dword.
+        if erase.is_empty() && dwords[0] & 0b11 == 0b01 {
+            erase.push(EraseType { size: 4096, opcode: (dwords[0] >> 8) as u8 });
+        }
+        if erase.is_empty() {
+            return Err(FlashError::InvalidSfdp { reason: "no erase commands" }.into());
+        }
+        erase.sort_by_key(|e| e.size);
+
+        // JESD216A added the page size, before that it's always 256.
+        let page_size = match dwords.get(10) {
+            Some(&dword) => 1 << ((dword >> 4) & 0xF),
+            None => 256
+        };
+
+        let addr_bytes = if (dwords[0] >> 17) & 0b11 == 0b10 || size > 1 << 24 { 4 } else { 3 };
+
+        Ok(Self { size: size, page_size: page_size, erase: erase, addr_bytes: addr_bytes })
+    }
+
+    /// For parts without SFDP: most vendors code the size as the
+    /// log2 of the capacity ID byte, and they all take 4K and 64K
+    /// erases.
+    pub fn guess(id: JedecId) -> Option<Self> {
+        if id.capacity < 0x10 || id.capacity > 0x19 {
+            return None;
+        }
+        let size = 1 << id.capacity;
+        Some(Self { size: size,
+                    page_size: 256,
+                    erase: vec![EraseType { size: 4096, opcode: 0x20 },
+                                EraseType { size: 65536, opcode: 0xD8 }],
+                    addr_bytes: if size > 1 << 24 { 4 } else { 3 } })
+    }
+
+    /// The smallest erase, which erases and writes are aligned to.
+    pub fn granularity(&self) -> usize {
+        self.erase[0].size
+    }
+}
+
+bitflags! {
+    /// Status register 1. Parts disagree above BP2, but BP3 (or a
+    /// top/bottom bit that only matters with the others set) and
+    /// SRWD are the usual layout.
+    pub struct Status: u8 {
+        const WIP  = 0b0000_0001;
+        const WEL  = 0b0000_0010;
+        const BP0  = 0b0000_0100;
+        const BP1  = 0b0000_1000;
+        const BP2  = 0b0001_0000;
+        const BP3  = 0b0010_0000;
+        const SRWD = 0b1000_0000;
+        /// The block protect bits.
+        const PROTECT = Self::BP0.bits | Self::BP1.bits | Self::BP2.bits | Self::BP3.bits;
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum FlashError {
+    #[fail(display="no flash answered (JEDEC ID {})", id)]
+    NoFlash { id: JedecId },
+    #[fail(display="flash {} has no SFDP tables and an unknown size", id)]
+    UnknownGeometry { id: JedecId },
+    #[fail(display="invalid SFDP: {}", reason)]
+    InvalidSfdp { reason: &'static str },
+    #[fail(display="{} bytes at {:#x} don't fit in a {} byte flash",
+           len, offset, size)]
+    OutOfRange { offset: usize, len: usize, size: usize },
+    #[fail(display="{} bytes at {:#x} aren't aligned to the {} byte erase size",
+           len, offset, granularity)]
+    Misaligned { offset: usize, len: usize, granularity: usize },
+    #[fail(display="{} didn't finish within {:?}", op, timeout)]
+    Timeout { op: &'static str, timeout: Duration },
+    #[fail(display="write enable didn't take (status {:#04x})", status)]
+    WriteEnableFailed { status: u8 },
+    #[fail(display="couldn't clear the block protect bits (status {:#04x}), is WP# held low?",
+           status)]
+    WriteProtected { status: u8 },
+    #[fail(display="verify failed at {:#x}: expected {:#04x}, read {:#04x}",
+           offset, expected, found)]
+    Mismatch { offset: usize, expected: u8, found: u8 }
+}
+
+/// A 25-series SPI NOR flash on the Pirate's CS.
+pub struct Flash<'a, T: Transport + 'a = SystemPort> {
+    conn: &'a mut SpiConn<T>,
+    id: JedecId,
+    geometry: Geometry,
+    // Whether we switched the part into 4-byte addressing, and have
+    // to switch it back.
+    entered_4byte: bool
+}
+
+impl<'a, T: Transport> Flash<'a, T> {
+    /// Identify the part and work out its geometry, from SFDP if it
+    /// has it and the JEDEC ID if not. SPI mode should already be
+    /// configured, with CS idling high.
+    pub fn new(conn: &'a mut SpiConn<T>) -> Result<Self, Error> {
+        let id = conn.write_then_read(&[READ_ID], 3)?;
+        let id = JedecId { manufacturer: id[0], memory_type: id[1], capacity: id[2] };
+        if id.manufacturer == 0x00 || id.manufacturer == 0xFF {
+            return Err(FlashError::NoFlash { id: id }.into());
+        }
+        let geometry = match read_sfdp(conn)? {
+            Some(geometry) => geometry,
+            None => Geometry::guess(id).ok_or(FlashError::UnknownGeometry { id: id })?
+        };
+        let mut flash = Self { conn: conn, id: id, geometry: geometry, entered_4byte: false };
+        if flash.geometry.addr_bytes == 4 {
+            // Harmless on 4-byte only parts, some others want WEL set.
+            flash.command(&[WRITE_ENABLE], 0)?;
+            flash.command(&[ENTER_4BYTE], 0)?;
+            flash.entered_4byte = true;
+        }
+        Ok(flash)
+    }
+
+    pub fn id(&self) -> JedecId {
+        self.id
+    }
+
+    pub fn geometry(&self) -> &Geometry {
+        &self.geometry
+    }
+
+    fn command(&mut self, write: &[u8], read_len: usize) -> Result<Vec<u8>, Error> {
+        self.conn.write_then_read(write, read_len)
+    }
+
+    // `opcode` followed by the address bytes for `offset`.
+    fn addressed(&self, opcode: u8, offset: usize) -> Vec<u8> {
+        let mut cmd = vec![opcode];
+        cmd.extend((0..self.geometry.addr_bytes).rev().map(|i| (offset >> (8 * i)) as u8));
+        cmd
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), Error> {
+        if offset + len > self.geometry.size {
+            return Err(FlashError::OutOfRange { offset: offset,
+                                                len: len,
+                                                size: self.geometry.size }.into());
+        }
+        Ok(())
+    }
+
+    pub fn status(&mut self) -> Result<Status, Error> {
+        let status = self.command(&[READ_STATUS], 1)?;
+        Ok(Status::from_bits_truncate(status[0]))
+    }
+
+    pub fn write_status(&mut self, status: Status) -> Result<(), Error> {
+        self.write_enable()?;
+        self.command(&[WRITE_STATUS, status.bits()], 0)?;
+        self.wait("status write", PROGRAM_TIMEOUT)
+    }
+
+    /// Clear the block protect bits, so the whole part can be erased
+    /// and programmed.
+    pub fn unprotect(&mut self) -> Result<(), Error> {
+        let status = self.status()?;
+        if !status.intersects(Status::PROTECT) {
+            return Ok(());
+        }
+        self.write_status(status - Status::PROTECT - Status::WEL)?;
+        let status = self.status()?;
+        if status.intersects(Status::PROTECT) {
+            return Err(FlashError::WriteProtected { status: status.bits() }.into());
+        }
+        Ok(())
+    }
+
+    fn write_enable(&mut self) -> Result<(), Error> {
+        self.command(&[WRITE_ENABLE], 0)?;
+        let status = self.status()?;
+        if !status.contains(Status::WEL) {
+            return Err(FlashError::WriteEnableFailed { status: status.bits() }.into());
+        }
+        Ok(())
+    }
+
+    // Poll the status register until the write in progress bit clears.
+    fn wait(&mut self, op: &'static str, timeout: Duration) -> Result<(), Error> {
+        let start = Instant::now();
+        while self.status()?.contains(Status::WIP) {
+            if start.elapsed() > timeout {
+                return Err(FlashError::Timeout { op: op, timeout: timeout }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Fast read of `len` bytes from `offset`.
+    pub fn read(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        self.check_range(offset, len)?;
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let at = offset + data.len();
+            let n = (len - data.len()).min(MAX_READ);
+            let mut cmd = self.addressed(FAST_READ, at);
+            cmd.push(0x00); // dummy byte
+            data.extend(self.command(&cmd, n)?);
+        }
+        Ok(data)
+    }
+
+    /// Erase `len` bytes from `offset`, both multiples of the smallest
+    /// erase size, with the biggest erases that fit. Erasing the
+    /// whole part uses chip erase.
+    pub fn erase(&mut self, offset: usize, len: usize) -> Result<(), Error> {
+        self.check_range(offset, len)?;
+        let granularity = self.geometry.granularity();
+        if !offset.is_multiple_of(granularity) || !len.is_multiple_of(granularity) {
+            return Err(FlashError::Misaligned { offset: offset,
+                                                len: len,
+                                                granularity: granularity }.into());
+        }
+        if offset == 0 && len == self.geometry.size {
+            return self.erase_chip();
+        }
+        let end = offset + len;
+        let mut at = offset;
+        while at < end {
+            // The smallest erase always fits, so this finds one.
+            let erase = *self.geometry.erase.iter().rev()
+                .find(|e| at.is_multiple_of(e.size) && at + e.size <= end)
+                .unwrap();
+            self.write_enable()?;
+            let cmd = self.addressed(erase.opcode, at);
+            self.command(&cmd, 0)?;
+            self.wait("erase", ERASE_TIMEOUT)?;
+            at += erase.size;
+        }
+        Ok(())
+    }
+
+    pub fn erase_chip(&mut self) -> Result<(), Error> {
+        self.write_enable()?;
+        self.command(&[CHIP_ERASE], 0)?;
+        self.wait("chip erase", CHIP_ERASE_TIMEOUT)
+    }
+
+    /// Page program `data` from `offset` into already erased flash.
+    /// Pages that are all 0xFF are skipped.
+    pub fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.check_range(offset, data.len())?;
+        let page_size = self.geometry.page_size;
+        let mut done = 0;
+        while done < data.len() {
+            let at = offset + done;
+            let n = (data.len() - done).min(page_size - at % page_size);
+            let page = &data[done..done + n];
+            done += n;
+            if page.iter().all(|&b| b == 0xFF) {
+                continue;
+            }
+            self.write_enable()?;
+            let mut cmd = self.addressed(PAGE_PROGRAM, at);
+            cmd.extend(page);
+            self.command(&cmd, 0)?;
+            self.wait("page program", PROGRAM_TIMEOUT)?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` from `offset`, one erase block at a time. Blocks
+    /// that already hold the data are left alone, and blocks are only
+    /// erased when a bit has to go from 0 to 1; the rest of an erased
+    /// block is written back as it was.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.check_range(offset, data.len())?;
+        let granularity = self.geometry.granularity();
+        let end = offset + data.len();
+        let mut block = offset - offset % granularity;
+        while block < end {
+            let old = self.read(block, granularity)?;
+            let lo = block.max(offset);
+            let hi = (block + granularity).min(end);
+            let mut new = old.clone();
+            new[lo - block..hi - block].copy_from_slice(&data[lo - offset..hi - offset]);
+
+            if new != old {
+                if old.iter().zip(&new).any(|(o, n)| n & !o != 0) {
+                    self.erase(block, granularity)?;
+                    self.program(block, &new)?;
+                } else {
+                    self.program(lo, &new[lo - block..hi - block])?;
+                }
+            }
+            block += granularity;
+        }
+        Ok(())
+    }
+
+    /// Read back from `offset` and compare with `data`, failing at the
+    /// first difference.
+    pub fn verify(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let found = self.read(offset, data.len())?;
+        match data.iter().zip(&found).position(|(a, b)| a != b) {
+            Some(i) => Err(FlashError::Mismatch { offset: offset + i,
+                                                  expected: data[i],
+                                                  found: found[i] }.into()),
+            None => Ok(())
+        }
+    }
+}
+
+impl<'a, T: Transport> Drop for Flash<'a, T> {
+    fn drop(&mut self) {
+        // Leave the part in 3-byte mode for whatever boots from it.
+        if self.entered_4byte {
+            let _ = self.command(&[EXIT_4BYTE], 0);
+        }
+    }
+}
+
+// Read `len` bytes of the SFDP area, which always has 3-byte addresses
+// and a dummy byte.
+fn sfdp_read<T: Transport>(conn: &mut SpiConn<T>, addr: usize, len: usize)
+                           -> Result<Vec<u8>, Error> {
+    conn.write_then_read(&[READ_SFDP, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8, 0x00],
+                         len)
+}
+
+// Find and parse the basic flash parameter table, None if the part
+// has no SFDP.
+fn read_sfdp<T: Transport>(conn: &mut SpiConn<T>) -> Result<Option<Geometry>, Error> {
+    let header = sfdp_read(conn, 0, 8)?;
+    if &header[..4] != b"SFDP" {
+        return Ok(None);
+    }
+    let headers = header[6] as usize + 1;
+    let params = sfdp_read(conn, 8, headers * 8)?;
+    // Parameter ID 0xFF00 is the basic table, JESD216 puts it first.
+    let bfpt = match params.chunks(8).find(|h| h[0] == 0x00 && h[7] == 0xFF) {
+        Some(h) => h,
+        None => return Err(FlashError::InvalidSfdp {
+            reason: "no basic flash parameter table"
+        }.into())
+    };
+    let len = bfpt[3] as usize;
+    let addr = bfpt[4] as usize | (bfpt[5] as usize) << 8 | (bfpt[6] as usize) << 16;
+    let table = sfdp_read(conn, addr, len * 4)?;
+    let dwords: Vec<u32> = table.chunks(4)
+        .map(|d| u32::from(d[0]) | u32::from(d[1]) << 8 |
+                 u32::from(d[2]) << 16 | u32::from(d[3]) << 24)
+        .collect();
+    Geometry::from_bfpt(&dwords).map(Some)
+}
+
+// 0x9F - Read JEDEC ID
+// Returns the manufacturer ID byte, then the memory type and
+// capacity bytes the manufacturer assigns.
+//
+// 0x5A - Read SFDP (JESD216)
+// Followed by a 3-byte address and a dummy byte, reads the Serial
+// Flash Discoverable Parameters area. It starts with an 8 byte
+// header: "SFDP", minor and major revision, the number of parameter
+// headers minus one and 0xFF. Each 8 byte parameter header that
+// follows holds the parameter ID LSB, minor and major revision,
+// length in dwords, a 3-byte table pointer and the parameter ID MSB.
+//
+// The basic flash parameter table (ID 0xFF00) gives, among others:
+//   dword 1: bits 1:0 4K erase supported (01), bits 15:8 its opcode,
+//            bits 18:17 address bytes (00 3-byte, 01 3 or 4, 10 4-byte)
+//   dword 2: density in bits, N+1 or 2^N when bit 31 is set
+//   dword 8-9: erase types 1-4, each a 2^N size byte and an opcode
+//   dword 11: bits 7:4 page size as 2^N (JESD216A and later)
+//
+// 0x06 / 0x05 / 0x01 - Write enable / read status / write status
+// Erase, program and status writes need the write enable latch (WEL)
+// set first, it clears when they finish. Status bit 0 (WIP) is set
+// while they run, bits 2-5 protect blocks from writes and SRWD locks
+// the status register while WP# is low.
+//
+// 0x0B - Fast read, address and a dummy byte, then any number of bytes
+// 0x02 - Page program, address and 1 byte to a page of data
+// 0x20 / 0x52 / 0xD8 - 4K / 32K / 64K erase (usually, SFDP says)
+// 0xC7 - Chip erase
+// 0xB7 / 0xE9 - Enter / exit 4-byte address mode
@@ -0,0 +1,45 @@
+use serial::{SerialPort, SystemPort};
+use serial::core::Result;
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+/// A byte stream a Bus Pirate can be driven over.
+///
+/// Reads are expected to fail with `ErrorKind::TimedOut` once the
+/// timeout elapses without data, the same way a serial port does; the
+/// handshake code relies on that to detect the end of a reply.
+pub trait Transport: Read + Write {
+    fn timeout(&self) -> Duration;
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()>;
+}
+
+impl Transport for SystemPort {
+    fn timeout(&self) -> Duration {
+        SerialPort::timeout(self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        SerialPort::set_timeout(self, timeout)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn timeout(&self) -> Duration {
+        (**self).timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        (**self).set_timeout(timeout)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn timeout(&self) -> Duration {
+        (**self).timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        (**self).set_timeout(timeout)
+    }
+}
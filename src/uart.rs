@@ -0,0 +1,436 @@
+use serial::SystemPort;
+use std::str::FromStr;
+use std::result::Result;
+use std::io::{self, Read, Write};
+
+use failure::Error;
+
+use super::info::{self, Capabilities, PirateInfo};
+use super::protocol::{self, Command, InvalidReply};
+use super::transport::Transport;
+
+/// A Bus Pirate in binary UART mode.
+///
+/// Bytes written through `Write` are sent to the target with bulk
+/// UART writes. Bytes from the target only arrive once RX echo is on
+/// (see `echo_rx`) and are read back through `Read`. Since echoed data
+/// and command replies share the one stream, change settings with echo
+/// off.
+pub struct UartConn<T: Transport = SystemPort> {
+    port: T,
+    info: Option<PirateInfo>,
+    rx: Vec<u8>,
+    echoing: bool,
+    bridged: bool
+}
+
+pub type BusSettings = protocol::BusSettings<Speed, Config>;
+
+#[derive(Debug, Fail)]
+enum CallError {
+    #[fail(display="can't send {} bytes in one bulk write (1-16)", len)]
+    InvalidLength { len: usize },
+    #[fail(display="UART is in transparent bridge mode")]
+    Bridged,
+    #[fail(display="can't write while RX echo is on")]
+    Echoing
+}
+
+// Fosc is 32MHz, the UART runs from Fcy = Fosc/2 with BRGH=1.
+const UART_FCY: u32 = 16_000_000;
+
+/// The BRG register value that gets closest to `baud`.
+pub fn brg_for_baud(baud: u32) -> u16 {
+    let baud = baud.max(1);
+    let brg = (UART_FCY + 2 * baud) / (4 * baud);
+    brg.saturating_sub(1).min(u16::MAX as u32) as u16
+}
+
+impl<T: Transport> UartConn<T> {
+    pub fn new(port: T) -> Self {
+        Self::with_info(port, None)
+    }
+
+    /// A connection that knows which Pirate it's talking to.
+    pub fn with_info(port: T, info: Option<PirateInfo>) -> Self {
+        Self { port: port, info: info, rx: Vec::new(), echoing: false, bridged: false }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        info::capabilities(self.info.as_ref())
+    }
+
+    pub fn test(&mut self) -> Result<(), Error> {
+        self.call(&Message::UartVSN)?;
+        Ok(())
+    }
+
+    fn call(&mut self, msg: &Message) -> Result<Vec<u8>, Error> {
+        if self.bridged {
+            return Err(CallError::Bridged.into());
+        }
+        protocol::call(&mut self.port, msg)
+    }
+
+    pub fn configure(&mut self, settings: &BusSettings) -> Result<(), Error> {
+        self.call(&Message::SetSpeed(settings.speed))?;
+        self.call(&Message::SetConfig(settings.config))?;
+        self.call(&Message::Configure(settings.power,
+                                      settings.pullups,
+                                      settings.aux,
+                                      settings.cs))?;
+        Ok(())
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) -> Result<(), Error> {
+        self.call(&Message::SetSpeed(speed))?;
+        Ok(())
+    }
+
+    /// Load the baud rate generator directly.
+    pub fn set_brg(&mut self, brg: u16) -> Result<(), Error> {
+        self.call(&Message::SetBRG(brg))?;
+        Ok(())
+    }
+
+    /// Set a baud rate that isn't one of the `Speed` presets.
+    pub fn set_custom_baud(&mut self, baud: u32) -> Result<(), Error> {
+        self.set_brg(brg_for_baud(baud))
+    }
+
+    pub fn set_config(&mut self, config: Config) -> Result<(), Error> {
+        self.call(&Message::SetConfig(config))?;
+        Ok(())
+    }
+
+    pub fn set_peripherals(&mut self, power: bool, pullups: bool,
+                           aux: bool, cs: bool) -> Result<(), Error> {
+        self.call(&Message::Configure(power, pullups, aux, cs))?;
+        Ok(())
+    }
+
+    /// Turn forwarding of bytes received from the target on or off.
+    ///
+    /// When turning echo off, target data that arrived before the
+    /// acknowledgement is kept and handed out by `read` as usual.
+    pub fn echo_rx(&mut self, on: bool) -> Result<(), Error> {
+        if on {
+            self.call(&Message::StartEcho)?;
+            self.echoing = true;
+            return Ok(());
+        }
+        if self.bridged {
+            return Err(CallError::Bridged.into());
+        }
+        self.port.write_all(&Message::StopEcho.send()?)?;
+        // The ack is the last thing sent before the stream goes quiet.
+        let mut pending = Vec::new();
+        while let Some(byte) = protocol::next_byte(&mut self.port) {
+            pending.push(byte?);
+        }
+        match pending.pop() {
+            Some(0x01) => {
+                self.rx.extend(pending);
+                self.echoing = false;
+                Ok(())
+            }
+            last => {
+                pending.extend(last);
+                Err(InvalidReply { sent: Message::StopEcho,
+                                   expected: vec![0x01],
+                                   received: pending }.into())
+            }
+        }
+    }
+
+    /// Send 1-16 bytes to the target in one bulk UART write.
+    ///
+    /// Refused while RX echo is on: the acks would be mixed into the
+    /// echoed bytes with no way to tell them apart.
+    pub fn bulk_write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if self.echoing {
+            return Err(CallError::Echoing.into());
+        }
+        if bytes.is_empty() || bytes.len() > 16 {
+            return Err(CallError::InvalidLength { len: bytes.len() }.into());
+        }
+        self.call(&Message::BulkWrite(bytes.to_vec()))?;
+        Ok(())
+    }
+
+    /// Switch to the transparent UART bridge.
+    ///
+    /// From here on `read` and `write` pass bytes straight through to
+    /// the target and every other command fails. The Pirate only
+    /// leaves bridge mode when it is reset or unplugged, so nothing is
+    /// sent on drop either.
+    pub fn bridge(&mut self) -> Result<(), Error> {
+        if self.bridged {
+            return Ok(());
+        }
+        self.port.write_all(&Message::Bridge.send()?)?;
+        self.bridged = true;
+        Ok(())
+    }
+}
+
+impl<T: Transport> Read for UartConn<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.rx.is_empty() {
+            return self.port.read(buf);
+        }
+        let n = buf.len().min(self.rx.len());
+        buf[..n].copy_from_slice(&self.rx[..n]);
+        self.rx.drain(..n);
+        Ok(n)
+    }
+}
+
+impl<T: Transport> Write for UartConn<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.bridged {
+            return self.port.write(buf);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = buf.len().min(16);
+        match self.bulk_write(&buf[..n]) {
+            Ok(()) => Ok(n),
+            Err(e) => Err(io::Error::other(e.compat()))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl<T: Transport> Drop for UartConn<T> {
+    fn drop(&mut self) {
+        if self.bridged {
+            return;
+        }
+        if self.echoing {
+            let _ = self.echo_rx(false);
+        }
+        let _ = self.call(&Message::Configure(false,false,false,false));
+        let _ = self.call(&Message::ExitToBBIO);
+    }
+}
+
+// 00000000 - Exit to bitbang mode, responds "BBIOx"
+// This command resets the Bus Pirate into raw bitbang mode from the
+// user terminal. It also resets to raw bitbang mode from raw UART
+// mode, or any other protocol mode. This command always returns a
+// five byte bitbang version string "BBIOx", where x is the current
+// bitbang protocol version (currently 1).
+//
+// 00000001 - Display mode version string, responds "ARTx"
+// Once in binary UART mode, send 0x01 to get the current mode
+// version string. The Bus Pirate responds 'ARTx', where x is the raw
+// UART protocol version (currently 1). Get the version string at any
+// time by sending 0x01 again. This command is the same in all binary
+// modes, the current mode can always be determined by sending 0x01.
+//
+// 0000001x - Start (0)/stop(1) echo UART RX
+// In binary UART mode the UART is always active and receiving. Incoming
+// data is only copied to the USB side if UART RX echo is
+// enabled. This allows you to configure and control the UART mode
+// settings without random data colliding with response codes. UART
+// mode starts with echo disabled. This mode has no impact on data
+// transmissions.
+//
+// Responds 0x01. Clears buffer overrun bit.
+//
+// 00000111 - Manual baud rate configuration, send 2 bytes
+// Configures the UART using custom baud rate generator settings. This
+// command is followed by two data bytes that represent the BRG
+// register value. Send the high 8 bits first, then the low 8
+// bits. Use the UART manual or an online calculator to find the
+// correct value (key values: fosc 32mHz, clock divider = 2,
+// BRGH=1). Bus Pirate responds 0x01 to each byte. Settings take
+// effect immediately.
+//
+// 00001111 - UART bridge mode (reset to exit)
+// Starts a transparent UART bridge using the current configuration.
+// Unplug the Bus Pirate to exit.
+//
+// 0001xxxx - Bulk UART write, send 1-16 bytes (0=1byte!)
+// Bulk write transfers a packet of xxxx+1 bytes to the UART. Up to 16
+// data bytes can be sent at once. Note that 0000 indicates 1 byte
+// because there's no reason to send 0. BP replies 0x01 to each byte.
+//
+// 0100wxyz - Configure peripherals w=power, x=pullups, y=AUX, z=CS
+// Enable (1) and disable (0) Bus Pirate peripherals and pins. Bit w
+// enables the power supplies, bit x toggles the on-board pull-up
+// resistors, y sets the state of the auxiliary pin, and z sets the
+// chip select pin. Features not present in a specific hardware
+// version are ignored. Bus Pirate responds 0x01 on success.
+//
+// Note: CS pin always follows the current HiZ pin configuration. AUX
+// is always a normal pin output (0=GND, 1=3.3volts).
+//
+// 0110xxxx - Set UART speed
+// Set the UART at a preconfigured speed value: 0000=300, 0001=1200,
+// 0010=2400, 0011=4800, 0100=9600, 0101=19200, 0110=31250 (MIDI),
+// 0111=38400, 1000=57600, 1010=115200
+// Start default is 300 baud. Bus Pirate responds 0x01 on
+// success. A read command is planned but not implemented in this
+// version.
+//
+// 100wxxyz - Configure UART settings
+// w= pin output HiZ(0)/3.3v(1)
+// xx=databits and parity 8/N(0), 8/E(1), 8/O(2), 9/N(3)
+// y=stop bits 1(0)/2(1)
+// z=RX polarity idle 1 (0), idle 0 (1)
+// Startup default is 00000. Bus Pirate responds 0x01 on success.
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    ExitToBBIO,
+    UartVSN,
+    StartEcho,
+    StopEcho,
+    SetBRG(u16),
+    Bridge,
+    BulkWrite(Vec<u8>),
+    Configure(bool, bool, bool, bool),
+    SetSpeed(Speed),
+    SetConfig(Config)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Speed {
+    Baud300    = 0b0000,
+    Baud1200   = 0b0001,
+    Baud2400   = 0b0010,
+    Baud4800   = 0b0011,
+    Baud9600   = 0b0100,
+    Baud19200  = 0b0101,
+    Baud31250  = 0b0110,
+    Baud38400  = 0b0111,
+    Baud57600  = 0b1000,
+    Baud115200 = 0b1010
+}
+
+impl FromStr for Speed {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "300"    => Ok(Speed::Baud300),
+            "1200"   => Ok(Speed::Baud1200),
+            "2400"   => Ok(Speed::Baud2400),
+            "4800"   => Ok(Speed::Baud4800),
+            "9600"   => Ok(Speed::Baud9600),
+            "19200"  => Ok(Speed::Baud19200),
+            "31250"  => Ok(Speed::Baud31250),
+            "38400"  => Ok(Speed::Baud38400),
+            "57600"  => Ok(Speed::Baud57600),
+            "115200" => Ok(Speed::Baud115200),
+            _        => Err("Invalid uart baud rate")
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Output {
+    HiZ  = 0b0_0000,
+    V3_3 = 0b1_0000
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataParity {
+    Bits8None = 0b0000,
+    Bits8Even = 0b0100,
+    Bits8Odd  = 0b1000,
+    Bits9None = 0b1100
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopBits {
+    One = 0b00,
+    Two = 0b10
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdlePolarity {
+    High = 0b0,
+    Low  = 0b1
+}
+
+/// The 100wxxyz UART config bits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub output: Output,
+    pub data: DataParity,
+    pub stop: StopBits,
+    pub idle: IdlePolarity
+}
+
+impl Default for Config {
+    // Startup default, 00000: HiZ, 8/N/1, idle high.
+    fn default() -> Self {
+        Self { output: Output::HiZ,
+               data: DataParity::Bits8None,
+               stop: StopBits::One,
+               idle: IdlePolarity::High }
+    }
+}
+
+impl Config {
+    pub fn bits(&self) -> u8 {
+        self.output as u8 | self.data as u8 | self.stop as u8 | self.idle as u8
+    }
+}
+
+use self::Message::*;
+impl Message {
+    /// The bytes to send, or an `InvalidInput` error when the message
+    /// can't be encoded.
+    pub fn send(&self) -> io::Result<Vec<u8>> {
+        Ok(match *self {
+            ExitToBBIO => vec![0b00000000],
+            UartVSN => vec![0b00000001],
+            StartEcho => vec![0b00000010],
+            StopEcho => vec![0b00000011],
+            SetBRG(brg) => vec![0b00000111, (brg >> 8) as u8, brg as u8],
+            Bridge => vec![0b00001111],
+            BulkWrite(ref bytes) => return protocol::bulk(0b0001_0000, bytes),
+            Configure(power, pullups, aux, cs) =>
+                protocol::configure(power, pullups, aux, cs),
+            SetSpeed(speed) => {
+                vec![0b0110_0000 | speed as u8]
+            },
+            SetConfig(config) => {
+                vec![0b1000_0000 | config.bits()]
+            }
+        })
+    }
+
+    pub fn expect(&self) -> Option<Vec<u8>> {
+        match *self {
+            ExitToBBIO  => Some(vec![b'B', b'B', b'I', b'O', b'1']),
+            UartVSN  => Some(vec![b'A', b'R', b'T', b'1']),
+            StartEcho => Some(vec![0b00000001]),
+            StopEcho => Some(vec![0b00000001]),
+            SetBRG(_) => Some(vec![0b00000001; 3]),
+            BulkWrite(ref bytes) => Some(vec![0b00000001; bytes.len() + 1]),
+            Configure(_,_,_,_) => Some(vec![0b00000001]),
+            SetSpeed(_) => Some(vec![0b00000001]),
+            SetConfig(_) => Some(vec![0b00000001]),
+            _ => None
+        }
+    }
+}
+
+impl Command for Message {
+    fn send(&self) -> io::Result<Vec<u8>> {
+        Message::send(self)
+    }
+
+    fn expect(&self) -> Option<Vec<u8>> {
+        Message::expect(self)
+    }
+}
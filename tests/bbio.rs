@@ -0,0 +1,131 @@
+extern crate ruspirate;
+extern crate serial;
+
+mod common;
+
+use ruspirate::BusPirate;
+use ruspirate::bbio::{BBIOConn, BinModeVSN, Pin, Pins, Prescaler, PwmSettings};
+use ruspirate::info::{Capabilities, Unsupported};
+use ruspirate::sim::{Mode, Simulator};
+
+use common::Canned;
+
+const V3_BANNER: &str = "Bus Pirate v3.5\r\nFirmware v5.10 (r559)  Bootloader v4.4\r\n";
+
+#[test]
+fn pins() {
+    let mut sim = Simulator::new();
+    sim.set_pin_levels(Pins::MISO | Pins::CS);
+    let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    // Only MISO is left an input, so CS reads as driven low.
+    assert_eq!(bbio.configure_pins(Pins::MISO).unwrap(), Pins::MISO);
+    assert_eq!(bbio.set_pins(Pins::POWER | Pins::CS | Pins::AUX).unwrap(),
+               Pins::POWER | Pins::CS | Pins::AUX | Pins::MISO);
+    assert_eq!(bbio.set_pin(Pin::AUX.into(), false).unwrap(),
+               Pins::POWER | Pins::CS | Pins::MISO);
+    let state = bbio.set_direction(Pins::CS, true).unwrap();
+    assert_eq!(state, Pins::POWER | Pins::CS | Pins::MISO);
+    assert_eq!(bbio.read_pins().unwrap(), state);
+}
+
+#[test]
+fn pwm_settings() {
+    let pwm = PwmSettings::calculate(1000.0, 0.5).unwrap();
+    assert_eq!(pwm.prescaler, Prescaler::Div1);
+    assert_eq!(pwm.period_register, 15999);
+    assert_eq!(pwm.duty_register, 8000);
+    assert_eq!(pwm.frequency, 1000.0);
+    let pwm = PwmSettings::calculate(50.0, 0.1).unwrap();
+    assert_eq!(pwm.prescaler, Prescaler::Div8);
+    assert_eq!(pwm.period_register, 39999);
+    assert_eq!(PwmSettings::calculate(8e6, 0.5).unwrap().period_register, 1);
+    assert!(PwmSettings::calculate(0.5, 0.5).is_none());
+    assert!(PwmSettings::calculate(20e6, 0.5).is_none());
+    assert!(PwmSettings::calculate(1e3, 1.5).is_none());
+}
+
+#[test]
+fn pwm() {
+    let mut sim = Simulator::new();
+    {
+        let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+        assert_eq!(bbio.set_pwm(1000.0, 0.25).unwrap().duty, 0.25);
+    }
+    assert_eq!(sim.pwm(), Some((0, 4000, 15999)));
+    {
+        let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+        bbio.clear_pwm().unwrap();
+    }
+    assert_eq!(sim.pwm(), None);
+}
+
+#[test]
+fn voltage() {
+    let mut sim = Simulator::new();
+    sim.set_probe_voltage(3.3);
+    {
+        let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+        let volts = bbio.probe_voltage().unwrap();
+        assert!((volts - 3.3).abs() < 0.01, "{}", volts);
+        {
+            let samples = bbio.continuous_voltage().unwrap().take(5)
+                .collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(samples.len(), 5);
+            assert_eq!(samples[0].adc, 512);
+        }
+        // Stopping the stream leaves the connection in step.
+        let volts = bbio.probe_voltage().unwrap();
+        assert!((volts - 3.3).abs() < 0.01, "{}", volts);
+    }
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn voltage_stream_ends_on_error() {
+    let mut port = Canned::new(&[0x02, 0x00, 0x01, 0x00]);
+    {
+        let mut bbio = BBIOConn::new(&mut port, BinModeVSN::One);
+        let mut stream = bbio.continuous_voltage().unwrap();
+        assert_eq!(stream.next().unwrap().unwrap().adc, 512);
+        assert_eq!(stream.next().unwrap().unwrap().adc, 256);
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
+    assert_eq!(port.sent, vec![0x15, 0x00, 0x00]);
+}
+
+#[test]
+fn frequency() {
+    let mut sim = Simulator::new();
+    sim.set_aux_frequency(12_345_678);
+    let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    assert_eq!(bbio.measure_frequency().unwrap(), 12_345_678);
+}
+
+#[test]
+fn self_test() {
+    let mut sim = Simulator::new().with_banner(V3_BANNER);
+    {
+        let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+        let report = bbio.self_test(false).unwrap();
+        assert!(report.passed());
+        assert!(!report.long);
+    }
+    sim.set_self_test_errors(3);
+    // Back from bitbang mode the version has to be read again.
+    let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    bbio.read_info().unwrap();
+    let report = bbio.self_test(true).unwrap();
+    assert_eq!(report.errors, 3);
+    assert!(report.long);
+}
+
+#[test]
+fn self_test_needs_v3() {
+    // The simulator is a v4 by default.
+    let mut sim = Simulator::new();
+    let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    let err = bbio.self_test(false).unwrap_err();
+    let unsupported = err.downcast_ref::<Unsupported>().unwrap();
+    assert_eq!(unsupported.missing, Capabilities::SELF_TEST);
+}
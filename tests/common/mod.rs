@@ -0,0 +1,54 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Duration;
+
+use ruspirate::Transport;
+use serial;
+
+/// A transport that plays back canned replies, one byte per read,
+/// and times out once they run out. Everything written is kept.
+pub struct Canned {
+    pub replies: VecDeque<u8>,
+    pub sent: Vec<u8>
+}
+
+impl Canned {
+    pub fn new(replies: &[u8]) -> Self {
+        Canned { replies: replies.iter().cloned().collect(), sent: vec![] }
+    }
+}
+
+impl Read for Canned {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.replies.pop_front() {
+            Some(byte) => {
+                buf[0] = byte;
+                Ok(1)
+            }
+            None => Err(ErrorKind::TimedOut.into())
+        }
+    }
+}
+
+impl Write for Canned {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Canned {
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(0)
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> serial::Result<()> {
+        Ok(())
+    }
+}
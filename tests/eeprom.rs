@@ -0,0 +1,71 @@
+extern crate ruspirate;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ruspirate::BusPirate;
+use ruspirate::eeprom::{Eeprom, EepromError, Part};
+use ruspirate::sim::{Memory, Simulator};
+
+fn memory(size: usize, addr_bytes: usize) -> Rc<RefCell<Memory>> {
+    Rc::new(RefCell::new(Memory::new(size, addr_bytes)))
+}
+
+#[test]
+fn part_names() {
+    assert_eq!("24LC256".parse::<Part>().unwrap(), Part::C256);
+    assert_eq!("at24c02".parse::<Part>().unwrap(), Part::C02);
+    assert_eq!("c16".parse::<Part>().unwrap(), Part::C16);
+    assert_eq!("1024".parse::<Part>().unwrap(), Part::C1024);
+    assert!("24c03".parse::<Part>().is_err());
+}
+
+#[test]
+fn read_write() {
+    let mut sim = Simulator::new();
+    let mem = memory(32768, 2);
+    // A2-A0 strapped to 011.
+    sim.add_i2c_device(0x53, Box::new(mem.clone()));
+    {
+        let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_i2c_mode().unwrap();
+        let mut eeprom = Eeprom::new(&mut i2c, Part::C256, 3);
+        // Crosses page boundaries at both ends.
+        let data: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        eeprom.write(40, &data).unwrap();
+        assert_eq!(eeprom.read(40, 300).unwrap(), data);
+        eeprom.verify(40, &data).unwrap();
+        let err = eeprom.verify(39, &data).unwrap_err();
+        match err.downcast_ref::<EepromError>() {
+            Some(&EepromError::Mismatch { offset: 39, expected: 0, found: 0xFF }) => (),
+            _ => panic!("{:?}", err)
+        }
+        let err = eeprom.read(32760, 16).unwrap_err();
+        match err.downcast_ref::<EepromError>() {
+            Some(&EepromError::OutOfRange { offset: 32760, len: 16, size: 32768 }) => (),
+            _ => panic!("{:?}", err)
+        }
+    }
+    assert_eq!(mem.borrow().data[40..340], (0..300u32).map(|i| i as u8).collect::<Vec<_>>()[..]);
+}
+
+#[test]
+fn block_select() {
+    // A 24C16 answers at eight addresses, one per 256 byte block.
+    let blocks: Vec<_> = (0..8).map(|_| memory(256, 1)).collect();
+    let mut sim = Simulator::new();
+    for (i, block) in blocks.iter().enumerate() {
+        sim.add_i2c_device(0x50 + i as u8, Box::new(block.clone()));
+    }
+    {
+        let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_i2c_mode().unwrap();
+        // The pins are ignored, all three bits select the block.
+        let mut eeprom = Eeprom::new(&mut i2c, Part::C16, 7);
+        let data: Vec<u8> = (0..20).collect();
+        eeprom.write(250, &data).unwrap();
+        assert_eq!(eeprom.read(250, 20).unwrap(), data);
+    }
+    assert_eq!(blocks[0].borrow().data[250..], [0, 1, 2, 3, 4, 5]);
+    assert_eq!(blocks[1].borrow().data[..14], (6..20).collect::<Vec<u8>>()[..]);
+}
@@ -0,0 +1,103 @@
+extern crate embedded_hal;
+extern crate ruspirate;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use embedded_hal::digital::{InputPin, OutputPin, StatefulOutputPin};
+use embedded_hal::i2c::{Error, ErrorKind, I2c, NoAcknowledgeSource, Operation};
+use embedded_hal::spi::{self, SpiBus, SpiDevice};
+
+use ruspirate::BusPirate;
+use ruspirate::bbio::{Pin, Pins};
+use ruspirate::hal::BitbangPin;
+use ruspirate::sim::{self, Memory, Simulator};
+
+// Answers with a count of the bytes clocked so far, and remembers
+// what it got.
+#[derive(Default)]
+struct Counter {
+    count: u8,
+    received: Vec<u8>
+}
+
+impl sim::SpiDevice for Counter {
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        self.received.push(mosi);
+        self.count = self.count.wrapping_add(1);
+        self.count
+    }
+}
+
+#[test]
+fn i2c() {
+    let mut sim = Simulator::new();
+    sim.add_i2c_device(0x50, Box::new(Rc::new(RefCell::new(Memory::new(64, 1)))));
+    let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_i2c_mode().unwrap();
+    I2c::write(&mut i2c, 0x50, &[0, 1, 2, 3, 4]).unwrap();
+    let mut buf = [0; 4];
+    I2c::write_read(&mut i2c, 0x50, &[0], &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+    // The pointer carries on past the bytes just read.
+    let mut buf = [0; 2];
+    I2c::read(&mut i2c, 0x50, &mut buf).unwrap();
+    assert_eq!(buf, [0xFF, 0xFF]);
+
+    let (mut first, mut second) = ([0; 1], [0; 1]);
+    i2c.transaction(0x50, &mut [Operation::Write(&[1]),
+                                Operation::Read(&mut first),
+                                Operation::Read(&mut second)]).unwrap();
+    assert_eq!((first, second), ([2], [3]));
+
+    let err = I2c::write(&mut i2c, 0x51, &[0]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown));
+    let err = i2c.transaction(0x51, &mut [Operation::Write(&[0])]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+}
+
+#[test]
+fn spi() {
+    let counter = Rc::new(RefCell::new(Counter::default()));
+    let mut sim = Simulator::new();
+    sim.set_spi_device(Box::new(counter.clone()));
+    let mut spi = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_spi_mode().unwrap();
+    spi.cs_low().unwrap();
+    // Reads longer than the write are padded with zeros.
+    let mut read = [0; 3];
+    SpiBus::transfer(&mut spi, &mut read, &[9]).unwrap();
+    assert_eq!(read, [1, 2, 3]);
+    SpiBus::write(&mut spi, &[7; 20]).unwrap();
+    spi.cs_high().unwrap();
+    assert_eq!(counter.borrow().received[..4], [9, 0, 0, 7]);
+
+    let mut id = [0; 2];
+    SpiDevice::transaction(&mut spi, &mut [spi::Operation::Write(&[0x9F]),
+                                           spi::Operation::Read(&mut id)]).unwrap();
+    assert_eq!(id, [25, 26]);
+    let mut buf = [0; 2];
+    SpiDevice::transaction(&mut spi, &mut [spi::Operation::Write(&[0x9F]),
+                                           spi::Operation::DelayNs(10),
+                                           spi::Operation::TransferInPlace(&mut buf)])
+        .unwrap();
+    assert_eq!(buf, [28, 29]);
+}
+
+#[test]
+fn bitbang_pins() {
+    let mut sim = Simulator::new();
+    sim.set_pin_levels(Pins::MISO);
+    {
+        let bbio = Rc::new(RefCell::new(BusPirate::new(&mut sim).enter_bio_mode().unwrap()));
+        let mut aux = BitbangPin::output(bbio.clone(), Pin::AUX).unwrap();
+        let mut miso = BitbangPin::input(bbio.clone(), Pin::MISO).unwrap();
+        let mut clk = BitbangPin::input(bbio.clone(), Pin::CLK).unwrap();
+        aux.set_high().unwrap();
+        assert!(aux.is_set_high().unwrap());
+        assert!(miso.is_high().unwrap());
+        assert!(clk.is_low().unwrap());
+    }
+    assert!(sim.pin_outputs().contains(Pins::AUX));
+    assert!(!sim.pin_inputs().contains(Pins::AUX));
+}
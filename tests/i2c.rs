@@ -0,0 +1,253 @@
+extern crate ruspirate;
+extern crate serial;
+
+mod common;
+
+use std::cell::RefCell;
+use std::io::ErrorKind;
+use std::rc::Rc;
+
+use ruspirate::BusPirate;
+use ruspirate::i2c::{BusSettings, I2CConn, I2CError, Message, PullUp, ScanResult, Speed,
+                     Transaction};
+use ruspirate::info::{Capabilities, Unsupported};
+use ruspirate::sim::{I2CDevice, Memory, Mode, Simulator};
+
+use common::Canned;
+
+const V3_BANNER: &str = "Bus Pirate v3.5\r\nFirmware v5.10 (r559)  Bootloader v4.4\r\n";
+
+fn memory(size: usize, addr_bytes: usize) -> Rc<RefCell<Memory>> {
+    Rc::new(RefCell::new(Memory::new(size, addr_bytes)))
+}
+
+// ACKs the first `room` data bytes of each write and NACKs the rest.
+struct Buffer {
+    room: usize,
+    written: usize
+}
+
+impl I2CDevice for Buffer {
+    fn select(&mut self, _read: bool) {
+        self.written = 0;
+    }
+
+    fn write(&mut self, _byte: u8) -> bool {
+        self.written += 1;
+        self.written <= self.room
+    }
+
+    fn read(&mut self) -> u8 {
+        0xFF
+    }
+}
+
+#[test]
+fn write_then_read() {
+    let mut sim = Simulator::new();
+    sim.add_i2c_device(0x50, Box::new(memory(256, 1)));
+    let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_i2c_mode().unwrap();
+    assert_eq!(i2c.write_then_read(0x50, &[0x10, 1, 2, 3], 0).unwrap(), vec![]);
+    assert_eq!(i2c.write_then_read(0x50, &[0x10], 3).unwrap(), vec![1, 2, 3]);
+    let err = i2c.write_then_read(0x51, &[0], 1).unwrap_err();
+    match err.downcast_ref::<I2CError>() {
+        Some(&I2CError::WriteThenReadFailed { addr: 0x51 }) => (),
+        _ => panic!("{:?}", err)
+    }
+    assert!(i2c.write_then_read(0x50, &[], 5000).is_err());
+    let err = i2c.write_then_read(0x80, &[0], 1).unwrap_err();
+    match err.downcast_ref::<I2CError>() {
+        Some(&I2CError::InvalidAddress { addr: 0x80 }) => (),
+        _ => panic!("{:?}", err)
+    }
+    // Still in step after the refusals.
+    i2c.test().unwrap();
+}
+
+#[test]
+fn scan() {
+    let mut sim = Simulator::new();
+    sim.add_i2c_device(0x50, Box::new(memory(256, 1)));
+    sim.add_i2c_device(0x03, Box::new(memory(256, 1)));
+    let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_i2c_mode().unwrap();
+    // 0x03 is reserved, so only turns up when nothing is skipped.
+    assert_eq!(i2c.scan().unwrap(),
+               vec![ScanResult { addr: 0x50, write: true, read: true }]);
+    let found: Vec<_> = i2c.scan_skipping(&[]).unwrap()
+        .into_iter().map(|found| found.addr).collect();
+    assert_eq!(found, vec![0x03, 0x50]);
+    i2c.test().unwrap();
+}
+
+#[test]
+fn primitives() {
+    let mut sim = Simulator::new();
+    sim.add_i2c_device(0x50, Box::new(memory(256, 1)));
+    let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_i2c_mode().unwrap();
+    i2c.start().unwrap();
+    assert_eq!(i2c.bulk_write(&[0xA0, 0x00, 7, 8]).unwrap(), vec![true; 4]);
+    i2c.stop().unwrap();
+
+    // Set the address pointer, then read back with a repeated start.
+    i2c.start().unwrap();
+    assert_eq!(i2c.bulk_write(&[0xA0, 0x00]).unwrap(), vec![true; 2]);
+    i2c.start().unwrap();
+    assert_eq!(i2c.bulk_write(&[0xA1]).unwrap(), vec![true]);
+    assert_eq!(i2c.read_byte().unwrap(), 7);
+    i2c.ack().unwrap();
+    assert_eq!(i2c.read_byte().unwrap(), 8);
+    i2c.nack().unwrap();
+    i2c.stop().unwrap();
+
+    // Nobody at 0x51.
+    i2c.start().unwrap();
+    assert_eq!(i2c.bulk_write(&[0xA2, 1]).unwrap(), vec![false, false]);
+    i2c.stop().unwrap();
+
+    assert!(i2c.bulk_write(&[0; 17]).is_err());
+    i2c.test().unwrap();
+}
+
+#[test]
+fn unencodable_messages() {
+    for msg in &[Message::BulkWrite(vec![]),
+                 Message::BulkWrite(vec![0; 17]),
+                 Message::WriteThenRead(vec![0; 4097], 0),
+                 Message::WriteThenRead(vec![], 4097)] {
+        assert_eq!(msg.send().unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+    assert_eq!(Message::BulkWrite(vec![0xA0, 1]).send().unwrap(), vec![0x11, 0xA0, 1]);
+}
+
+#[test]
+fn write() {
+    let mut sim = Simulator::new();
+    sim.add_i2c_device(0x50, Box::new(memory(64, 1)));
+    sim.add_i2c_device(0x60, Box::new(Buffer { room: 20, written: 0 }));
+    let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_i2c_mode().unwrap();
+    let data: Vec<u8> = (0..40).collect();
+    let mut stream = vec![0];
+    stream.extend(&data);
+    i2c.write(0x50, &stream).unwrap();
+    assert_eq!(i2c.write_then_read(0x50, &[0], 40).unwrap(), data);
+
+    let err = i2c.write(0x51, &[1]).unwrap_err();
+    match err.downcast_ref::<I2CError>() {
+        Some(&I2CError::AddressNack { addr: 0x51 }) => (),
+        _ => panic!("{:?}", err)
+    }
+    let err = i2c.write(0x60, &data).unwrap_err();
+    match err.downcast_ref::<I2CError>() {
+        Some(&I2CError::DataNack { addr: 0x60, offset: 20 }) => (),
+        _ => panic!("{:?}", err)
+    }
+    let err = i2c.write(0xA0, &data).unwrap_err();
+    match err.downcast_ref::<I2CError>() {
+        Some(&I2CError::InvalidAddress { addr: 0xA0 }) => (),
+        _ => panic!("{:?}", err)
+    }
+    i2c.test().unwrap();
+}
+
+#[test]
+fn write_nack_outlives_failed_stop() {
+    // The start is ACKed and 0x51 NACKs its address, then the stop
+    // gets no reply at all.
+    let mut port = Canned::new(&[0x01, 0x01, 0x01, 0x00]);
+    let err = I2CConn::new(&mut port).write(0x51, &[1]).unwrap_err();
+    match err.downcast_ref::<I2CError>() {
+        Some(&I2CError::AddressNack { addr: 0x51 }) => (),
+        _ => panic!("{:?}", err)
+    }
+    // Start, a two byte bulk write, then the stop.
+    assert_eq!(&port.sent[..5], &[0x02, 0x11, 0xA2, 1, 0x03]);
+}
+
+#[test]
+fn sniff() {
+    // A register read: write the pointer, then a repeated start and
+    // two bytes read back, the last NACKed.
+    let mut port = Canned::new(b"[\\\xA0+\\\x10+[\\\xA1+\\\x55+\\\x66-]");
+    let mut i2c = I2CConn::new(&mut port);
+    let transactions: Vec<_> = i2c.sniff().unwrap().transactions()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(transactions,
+               vec![Transaction { addr: 0x50,
+                                  read: false,
+                                  addr_ack: true,
+                                  data: vec![0x10],
+                                  acks: vec![true],
+                                  repeated_start: true },
+                    Transaction { addr: 0x50,
+                                  read: true,
+                                  addr_ack: true,
+                                  data: vec![0x55, 0x66],
+                                  acks: vec![true, false],
+                                  repeated_start: false }]);
+}
+
+#[test]
+fn sniff_quiet_bus() {
+    let mut sim = Simulator::new();
+    {
+        let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_i2c_mode().unwrap();
+        assert_eq!(i2c.sniff().unwrap().count(), 0);
+        // Dropping the sniffer leaves it and gets back in step.
+        i2c.test().unwrap();
+    }
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn pullups() {
+    let mut sim = Simulator::new();
+    {
+        let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_i2c_mode().unwrap();
+        i2c.configure(&BusSettings::new(Speed::Hz100000, Some(PullUp::V3_3),
+                                        true, false, false)).unwrap();
+    }
+    sim.set_vextern(true);
+    // Back from bitbang mode the version has to be read again.
+    let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    bbio.read_info().unwrap();
+    let mut i2c = bbio.enter_i2c_mode().unwrap();
+    let err = i2c.configure(&BusSettings::new(Speed::Hz100000, Some(PullUp::V5),
+                                              true, false, false)).unwrap_err();
+    match err.downcast_ref::<I2CError>() {
+        Some(&I2CError::ExternalVoltage) => (),
+        _ => panic!("{:?}", err)
+    }
+}
+
+#[test]
+fn no_pullup_voltage_leaves_them_off() {
+    let mut port = Canned::new(&[0x01, 0x01]);
+    I2CConn::new(&mut port)
+        .configure(&BusSettings::new(Speed::Hz50000, Some(PullUp::None),
+                                     true, false, false)).unwrap();
+    // Set speed, then power on with the pull-ups bit clear and no
+    // voltage select in between.
+    assert_eq!(&port.sent[..2], &[0x61, 0b0100_1000]);
+}
+
+#[test]
+fn pullups_before_v4() {
+    let mut sim = Simulator::new().with_banner(V3_BANNER);
+    let mut i2c = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_i2c_mode().unwrap();
+    let err = i2c.select_pullup(PullUp::V5).unwrap_err();
+    let unsupported = err.downcast_ref::<Unsupported>().unwrap();
+    assert_eq!(unsupported.missing, Capabilities::PULLUP_SELECT);
+    // Asking configure for a voltage is refused the same way.
+    let err = i2c.configure(&BusSettings::new(Speed::Hz100000, Some(PullUp::V5),
+                                              true, false, false)).unwrap_err();
+    let unsupported = err.downcast_ref::<Unsupported>().unwrap();
+    assert_eq!(unsupported.missing, Capabilities::PULLUP_SELECT);
+    i2c.test().unwrap();
+}
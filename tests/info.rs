@@ -0,0 +1,145 @@
+extern crate ruspirate;
+extern crate serial;
+
+mod common;
+
+use ruspirate::{BusPirate, PirateInfo};
+use ruspirate::i2c::{BusSettings, I2CConn, Speed};
+use ruspirate::rawwire::RawWireConn;
+use ruspirate::info::{self, Capabilities, Unsupported, Version};
+use ruspirate::sim::Simulator;
+
+use common::Canned;
+
+const V3_BANNER: &str = "Bus Pirate v3.5\r\nFirmware v5.10 (r559)  Bootloader v4.4\r\n";
+
+#[test]
+fn read_info() {
+    let mut sim = Simulator::new();
+    let info = BusPirate::new(&mut sim).read_info().unwrap();
+    assert_eq!(info.hardware, "v4");
+    assert_eq!(info.firmware, Version::new(6, 2));
+    assert_eq!(info.firmware_suffix, "-beta1");
+    assert_eq!(info.revision, Some(1981));
+    assert_eq!(info.bootloader, None);
+    assert_eq!(info.devid, Some(0x1019));
+    assert_eq!(info.revid, Some(0x0004));
+    assert_eq!(info.mcu.as_deref(), Some("24FJ256GB106"));
+}
+
+#[test]
+fn parse_v3() {
+    let info: PirateInfo = "Bus Pirate v3.5\r\n\
+                            Firmware v5.10 (r559)  Bootloader v4.4\r\n\
+                            DEVID:0x0447 REVID:0x3046 (24FJ64GA002 B8)\r\n\
+                            http://dangerousprototypes.com".parse().unwrap();
+    assert_eq!(info.hardware, "v3.5");
+    assert_eq!(info.firmware, Version::new(5, 10));
+    assert_eq!(info.firmware_suffix, "");
+    assert_eq!(info.revision, Some(559));
+    assert_eq!(info.bootloader, Some(Version::new(4, 4)));
+    assert_eq!(info.devid, Some(0x0447));
+    assert_eq!(info.revid, Some(0x3046));
+    assert_eq!(info.mcu.as_deref(), Some("24FJ64GA002"));
+    // 5.10 is later than 5.9, not 5.1.
+    assert!(info.firmware > Version::new(5, 9));
+}
+
+#[test]
+fn parse_community_firmware() {
+    let info: PirateInfo = "Bus Pirate v3.b\r\n\
+                            Community Firmware v7.0 - goo.gl/gCzQnW [HiZ 1-WIRE UART I2C SPI 2WIRE 3WIRE KEYB LCD PIC DIO] Bootloader v4.4\r\n\
+                            DEVID:0x0447 REVID:0x3046 (24FJ64GA002 B8)".parse().unwrap();
+    assert_eq!(info.hardware, "v3.b");
+    assert_eq!(info.firmware, Version::new(7, 0));
+    assert_eq!(info.revision, None);
+    assert_eq!(info.bootloader, Some(Version::new(4, 4)));
+}
+
+#[test]
+fn parse_garbage() {
+    assert!("garbage".parse::<PirateInfo>().is_err());
+    assert!("Bus Pirate v4\nno firmware".parse::<PirateInfo>().is_err());
+}
+
+#[test]
+fn capabilities() {
+    let v4: PirateInfo = "Bus Pirate v4\nFirmware v6.2-beta1 r1981\n".parse().unwrap();
+    assert_eq!(v4.capabilities(),
+               Capabilities::PULLUP_SELECT | Capabilities::I2C_FAST_SPEEDS
+               | Capabilities::BULK_BITS);
+    let v3: PirateInfo = V3_BANNER.parse().unwrap();
+    assert_eq!(v3.capabilities(),
+               Capabilities::SELF_TEST | Capabilities::I2C_FAST_SPEEDS
+               | Capabilities::BULK_BITS);
+    let old: PirateInfo = "Bus Pirate v3\nFirmware v4.1 r1\n".parse().unwrap();
+    assert_eq!(old.capabilities(), Capabilities::SELF_TEST);
+    assert_eq!(info::capabilities(None), Capabilities::empty());
+
+    let unsupported = old.require(Capabilities::PULLUP_SELECT | Capabilities::SELF_TEST,
+                                  "pull-up voltage select").unwrap_err();
+    assert_eq!(unsupported.missing, Capabilities::PULLUP_SELECT);
+    assert!(v4.require(Capabilities::PULLUP_SELECT, "pull-up voltage select").is_ok());
+    // An unknown Pirate is refused everything version dependent.
+    let unsupported = info::require(None, Capabilities::SELF_TEST, "self-test").unwrap_err();
+    assert_eq!(unsupported.missing, Capabilities::SELF_TEST);
+    assert_eq!(unsupported.hardware, None);
+}
+
+#[test]
+fn every_mode_gets_the_info() {
+    // The version is only read on the way in from the terminal, so each
+    // mode starts from a freshly reset simulator.
+    let v3 = PirateInfo::parse(V3_BANNER).unwrap().capabilities();
+    let mut sim = Simulator::new().with_banner(V3_BANNER);
+    let bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    assert_eq!(bbio.enter_i2c_mode().unwrap().capabilities(), v3);
+    let mut sim = Simulator::new().with_banner(V3_BANNER);
+    let bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    assert_eq!(bbio.enter_spi_mode().unwrap().capabilities(), v3);
+    let mut sim = Simulator::new().with_banner(V3_BANNER);
+    let bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    assert_eq!(bbio.enter_uart_mode().unwrap().capabilities(), v3);
+    let mut sim = Simulator::new().with_banner(V3_BANNER);
+    let bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    assert_eq!(bbio.enter_onewire_mode().unwrap().capabilities(), v3);
+    let mut sim = Simulator::new().with_banner(V3_BANNER);
+    let bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    assert_eq!(bbio.enter_rawwire_mode().unwrap().capabilities(), v3);
+    let mut sim = Simulator::new().with_banner(V3_BANNER);
+    let bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+    assert_eq!(bbio.enter_jtag_mode().unwrap().capabilities(), v3);
+}
+
+#[test]
+fn old_firmware_i2c() {
+    let info: PirateInfo = "Bus Pirate v3\nFirmware v4.1 r1\n".parse().unwrap();
+    let mut port = Canned::new(&[0x01, 0x01, 0x01]);
+    {
+        let mut i2c = I2CConn::with_info(&mut port, Some(info));
+        // 50kHz and 5kHz keep their bits, and configure peripherals is
+        // 0100wxyz like on newer firmware: 0110wxyz would collide with
+        // the speed command.
+        i2c.configure(&BusSettings::new(Speed::Hz50000, None,
+                                        true, false, false)).unwrap();
+        let err = i2c.set_speed(Speed::Hz400000).unwrap_err();
+        assert_eq!(err.downcast_ref::<Unsupported>().unwrap().missing,
+                   Capabilities::I2C_FAST_SPEEDS);
+        i2c.set_speed(Speed::Hz5000).unwrap();
+    }
+    assert_eq!(port.sent[..3], [0x61, 0b0100_1000, 0x60]);
+}
+
+#[test]
+fn old_firmware_bulk_bits() {
+    let info: PirateInfo = "Bus Pirate v3\nFirmware v4.4 r1\n".parse().unwrap();
+    let mut port = Canned::new(&[]);
+    {
+        let mut raw = RawWireConn::with_info(&mut port, Some(info));
+        let err = raw.bulk_bits(0xA5, 5).unwrap_err();
+        assert_eq!(err.downcast_ref::<Unsupported>().unwrap().missing,
+                   Capabilities::BULK_BITS);
+    }
+    // The command itself never went out.
+    assert!(!port.sent.contains(&0b0011_0100));
+}
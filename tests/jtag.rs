@@ -0,0 +1,56 @@
+extern crate ruspirate;
+
+use std::io::ErrorKind;
+
+use ruspirate::BusPirate;
+use ruspirate::jtag::{Feature, IdCode, Message, PortMode, SerialSpeed, TapState};
+use ruspirate::sim::{Mode, Simulator};
+
+#[test]
+fn scan_chain() {
+    let mut sim = Simulator::new();
+    sim.add_jtag_device(Some(0x4BA00477), 4);
+    sim.add_jtag_device(None, 5);
+    sim.add_jtag_device(Some(0x06413041), 5);
+    sim.set_probe_voltage(3.3);
+    {
+        let mut jtag = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_jtag_mode().unwrap();
+        jtag.set_port_mode(PortMode::Jtag).unwrap();
+        jtag.set_feature(Feature::Vreg, true).unwrap();
+        let adcs = jtag.read_adcs().unwrap();
+        assert!((adcs.adc - 3.3).abs() < 0.01, "{:?}", adcs);
+        assert!((adcs.v3_3 - 3.3).abs() < 0.01, "{:?}", adcs);
+        assert_eq!(jtag.idcodes().unwrap(),
+                   vec![Some(IdCode(0x4BA00477)), None, Some(IdCode(0x06413041))]);
+        assert_eq!(jtag.state(), Some(TapState::RunTestIdle));
+        // IR capture is 0b..01 for each device.
+        assert_eq!(jtag.scan_ir(14, &[0xFF, 0xFF]).unwrap(), vec![0x11, 0x02]);
+        assert_eq!(jtag.scan_dr(8, &[0b1010_1010]).unwrap(), vec![0x50]);
+        jtag.set_serial_speed(SerialSpeed::Fast, |_, baud| {
+            assert_eq!(baud, 1_000_000);
+            Ok(())
+        }).unwrap();
+    }
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn tap_state_path() {
+    assert_eq!(TapState::TestLogicReset.path_to(TapState::ShiftIR),
+               vec![false, true, true, false, false]);
+}
+
+#[test]
+fn tap_shift_lengths() {
+    let err = Message::TapShift(9, vec![0], vec![0, 0]).send().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(Message::TapShift(9, vec![0, 0], vec![0, 0]).send().is_ok());
+
+    let mut sim = Simulator::new();
+    sim.add_jtag_device(Some(0x4BA00477), 4);
+    let mut jtag = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_jtag_mode().unwrap();
+    assert!(jtag.tap_shift(9, &[0], &[0, 0]).is_err());
+    assert!(jtag.tap_shift(9, &[0, 0], &[0, 0]).is_ok());
+}
@@ -0,0 +1,113 @@
+extern crate ruspirate;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ruspirate::BusPirate;
+use ruspirate::onewire::{crc8, RomId, SearchResult};
+use ruspirate::sim::{Mode, OneWireDevice, Simulator};
+
+// Answers every read with 0x42, and remembers what was written.
+struct Thermometer {
+    rom: RomId,
+    alarm: bool,
+    written: Vec<u8>
+}
+
+impl Thermometer {
+    fn new(serial: u64, alarm: bool) -> Self {
+        let mut bytes = [0; 8];
+        bytes[0] = 0x28;
+        for (i, byte) in bytes[1..7].iter_mut().enumerate() {
+            *byte = (serial >> (8 * i)) as u8;
+        }
+        bytes[7] = crc8(&bytes[..7]);
+        Thermometer { rom: RomId::from_bytes(bytes), alarm: alarm, written: vec![] }
+    }
+}
+
+impl OneWireDevice for Thermometer {
+    fn rom(&self) -> RomId {
+        self.rom
+    }
+
+    fn alarm(&self) -> bool {
+        self.alarm
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.written.push(byte);
+    }
+
+    fn read(&mut self) -> u8 {
+        0x42
+    }
+}
+
+#[test]
+fn rom_id() {
+    // The worked example from Maxim application note 27.
+    assert_eq!(crc8(&[0x02, 0x1C, 0xB8, 0x01, 0x00, 0x00, 0x00]), 0xA2);
+    let rom = Thermometer::new(0x123456, false).rom;
+    assert!(rom.is_valid());
+    assert_eq!(rom.family(), 0x28);
+    assert_eq!(rom.serial(), 0x123456);
+    assert!(!RomId(0x1234).is_valid());
+}
+
+#[test]
+fn search_and_select() {
+    let quiet = Rc::new(RefCell::new(Thermometer::new(0x123456, false)));
+    let alarmed = Thermometer::new(0x654321, true);
+    let quiet_rom = quiet.borrow().rom;
+    let alarmed_rom = alarmed.rom;
+    let mut sim = Simulator::new();
+    sim.add_onewire_device(Box::new(quiet.clone()));
+    sim.add_onewire_device(Box::new(alarmed));
+    {
+        let mut wire = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_onewire_mode().unwrap();
+        wire.test().unwrap();
+        let found = wire.search_rom().unwrap();
+        let mut roms = found.roms;
+        roms.sort_by_key(|rom| rom.0);
+        let mut expected = vec![quiet_rom, alarmed_rom];
+        expected.sort_by_key(|rom| rom.0);
+        assert_eq!(roms, expected);
+        assert!(found.bad_crc.is_empty());
+        assert_eq!(wire.search_alarm().unwrap().roms, vec![alarmed_rom]);
+        assert!(wire.presence().unwrap());
+        wire.select(&quiet_rom).unwrap();
+        wire.write(&[0xBE]).unwrap();
+        assert_eq!(wire.read(2).unwrap(), vec![0x42, 0x42]);
+    }
+    assert_eq!(quiet.borrow().written, vec![0xBE]);
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn search_keeps_good_roms() {
+    let good = Thermometer::new(0x123456, false);
+    let good_rom = good.rom;
+    let mut bad = Thermometer::new(0x654321, false);
+    bad.rom = RomId(bad.rom.0 ^ (0xFF << 56));
+    let bad_rom = bad.rom;
+    let mut sim = Simulator::new();
+    sim.add_onewire_device(Box::new(good));
+    sim.add_onewire_device(Box::new(bad));
+    let mut wire = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_onewire_mode().unwrap();
+    assert_eq!(wire.search_rom().unwrap(),
+               SearchResult { roms: vec![good_rom], bad_crc: vec![bad_rom] });
+}
+
+#[test]
+fn empty_bus() {
+    let mut sim = Simulator::new();
+    let mut wire = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_onewire_mode().unwrap();
+    // The reset is acknowledged whether or not anything answered it.
+    wire.reset().unwrap();
+    assert!(!wire.presence().unwrap());
+    assert!(wire.select_all().is_err());
+}
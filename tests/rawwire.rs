@@ -0,0 +1,49 @@
+extern crate ruspirate;
+
+use ruspirate::BusPirate;
+use ruspirate::rawwire::{BusSettings, Config, Speed, Wires};
+use ruspirate::sim::{Mode, Simulator};
+
+#[test]
+fn bulk_write() {
+    let mut sim = Simulator::new();
+    {
+        let mut raw = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_rawwire_mode().unwrap();
+        raw.test().unwrap();
+        let mut config = Config::default();
+        raw.configure(&BusSettings::new(Speed::Hz100000, config,
+                                        true, true, false, false)).unwrap();
+        // In 2-wire mode each byte is acked with 0x01...
+        assert_eq!(raw.bulk_write(&[1, 2, 3]).unwrap(), vec![0x01; 3]);
+        // ...and in 3-wire mode answered with what was read from MISO,
+        // which nothing drives here.
+        config.wires = Wires::Three;
+        raw.set_config(config).unwrap();
+        assert_eq!(raw.bulk_write(&[1, 2, 3]).unwrap(), vec![0xFF; 3]);
+    }
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn bit_level() {
+    let mut sim = Simulator::new();
+    let mut raw = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_rawwire_mode().unwrap();
+    raw.start_bit().unwrap();
+    raw.stop_bit().unwrap();
+    raw.cs_low().unwrap();
+    raw.cs_high().unwrap();
+    assert_eq!(raw.read_byte().unwrap(), 0xFF);
+    assert!(raw.read_bit().unwrap());
+    assert!(raw.peek().unwrap());
+    raw.clock_tick().unwrap();
+    raw.clock_low().unwrap();
+    raw.clock_high().unwrap();
+    raw.data_low().unwrap();
+    raw.data_high().unwrap();
+    raw.bulk_clock_ticks(16).unwrap();
+    raw.bulk_bits(0xA5, 5).unwrap();
+    assert!(raw.bulk_bits(0, 9).is_err());
+    raw.test().unwrap();
+}
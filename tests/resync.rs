@@ -0,0 +1,147 @@
+extern crate ruspirate;
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+use ruspirate::{BusPirate, PirateState};
+use ruspirate::sim::{Mode, Simulator, SpiDevice};
+
+// Send raw commands, dropping whatever comes back.
+fn send(sim: &mut Simulator, bytes: &[u8]) {
+    sim.write_all(bytes).unwrap();
+    let mut reply = [0; 64];
+    while let Ok(n) = sim.read(&mut reply) {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+// Resync from wherever `sim` was left, check the state it was found
+// in and that it ends up in bitbang mode. The version is only read
+// when coming from the terminal.
+fn resync(sim: &mut Simulator, expected: PirateState) {
+    {
+        let (bbio, state) = BusPirate::new(&mut *sim).resync().unwrap();
+        assert_eq!(state, expected);
+        let hardware = bbio.info.as_ref().map(|info| info.hardware.as_str());
+        if expected == PirateState::Terminal {
+            assert_eq!(hardware, Some("v4"));
+        } else {
+            assert_eq!(hardware, None);
+        }
+    }
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+// Keeps every byte clocked out to it.
+#[derive(Default)]
+struct Recorder {
+    received: Vec<u8>
+}
+
+impl SpiDevice for Recorder {
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        self.received.push(mosi);
+        0xFF
+    }
+}
+
+// Twenty 0x00s leave the terminal for bitbang mode.
+fn enter_bbio(sim: &mut Simulator) {
+    send(sim, &[0; 20]);
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn from_terminal() {
+    let mut sim = Simulator::new();
+    resync(&mut sim, PirateState::Terminal);
+}
+
+#[test]
+fn from_bitbang() {
+    let mut sim = Simulator::new();
+    enter_bbio(&mut sim);
+    // The query switches bitbang mode to SPI, so it can't say which.
+    resync(&mut sim, PirateState::SpiOrBitbang);
+}
+
+#[test]
+fn from_protocol_mode() {
+    let mut sim = Simulator::new();
+    enter_bbio(&mut sim);
+    send(&mut sim, &[0x03]);
+    assert_eq!(sim.mode(), Mode::UART);
+    resync(&mut sim, PirateState::Uart);
+}
+
+#[test]
+fn from_sniffer() {
+    let mut sim = Simulator::new();
+    enter_bbio(&mut sim);
+    send(&mut sim, &[0x02, 0x0F]);
+    assert_eq!(sim.mode(), Mode::I2CSniffer);
+    resync(&mut sim, PirateState::I2CSniffer);
+}
+
+#[test]
+fn from_jtag() {
+    let mut sim = Simulator::new();
+    enter_bbio(&mut sim);
+    send(&mut sim, &[0x06]);
+    assert_eq!(sim.mode(), Mode::Jtag);
+    resync(&mut sim, PirateState::Binary);
+}
+
+#[test]
+fn from_bulk_transfer() {
+    let flash = Rc::new(RefCell::new(Recorder::default()));
+    let mut sim = Simulator::new();
+    sim.set_spi_device(Box::new(flash.clone()));
+    enter_bbio(&mut sim);
+    // CS low, then an SPI bulk transfer waiting for its 16 bytes.
+    send(&mut sim, &[0x01, 0x02, 0x1F]);
+    resync(&mut sim, PirateState::MidCommand);
+    // The query and 0x00s completed it, and no terminal escape reached
+    // the target.
+    let mut expected = vec![0x01];
+    expected.extend(&[0x00; 15]);
+    assert_eq!(flash.borrow().received, expected);
+}
+
+#[test]
+fn from_write_then_read() {
+    let mut sim = Simulator::new();
+    enter_bbio(&mut sim);
+    // An SPI write then read waiting for 4096 bytes to write.
+    send(&mut sim, &[0x01, 0x04, 0x10, 0x00, 0x00, 0x00]);
+    resync(&mut sim, PirateState::MidCommand);
+}
+
+#[test]
+fn keeps_binary_mode_state() {
+    let mut sim = Simulator::new();
+    {
+        let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+        bbio.set_pwm(1000.0, 0.25).unwrap();
+    }
+    // Coming back from bitbang mode doesn't reset the Pirate...
+    drop(BusPirate::new(&mut sim).enter_bio_mode().unwrap());
+    assert_eq!(sim.pwm(), Some((0, 4000, 15999)));
+    {
+        // ...but reading the version does.
+        let mut bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+        assert_eq!(bbio.info, None);
+        assert_eq!(bbio.read_info().unwrap().hardware, "v4");
+    }
+    assert_eq!(sim.pwm(), None);
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn unparseable_banner() {
+    let mut sim = Simulator::new().with_banner("Not a Bus Pirate");
+    assert!(BusPirate::new(&mut sim).resync().is_err());
+}
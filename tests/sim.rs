@@ -0,0 +1,44 @@
+extern crate ruspirate;
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+use ruspirate::BusPirate;
+use ruspirate::i2c::{BusSettings, Speed};
+use ruspirate::sim::{Memory, Mode, Simulator};
+
+#[test]
+fn read_vsn_from_terminal() {
+    let mut sim = Simulator::new();
+    let vsn = BusPirate::new(&mut sim).read_vsn().unwrap();
+    assert!(vsn.starts_with("Bus Pirate v4\nFirmware"), "{:?}", vsn);
+    assert_eq!(sim.mode(), Mode::Terminal);
+}
+
+#[test]
+fn configure_i2c() {
+    let mut sim = Simulator::new();
+    sim.add_i2c_device(0x50, Box::new(Rc::new(RefCell::new(Memory::new(256, 1)))));
+    {
+        let bbio = BusPirate::new(&mut sim).enter_bio_mode().unwrap();
+        let mut i2c = bbio.enter_i2c_mode().unwrap();
+        i2c.configure(&BusSettings::new(Speed::Hz100000, None, true, false, false))
+            .unwrap();
+        i2c.test().unwrap();
+    }
+    // Dropping the connection goes back to bitbang mode.
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn unknown_bitbang_command() {
+    let mut sim = Simulator::new();
+    drop(BusPirate::new(&mut sim).enter_bio_mode().unwrap());
+    assert_eq!(sim.mode(), Mode::BBIO);
+    sim.write_all(&[0b0001_0111]).unwrap();
+    let mut reply = [0xFF; 1];
+    sim.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [0x00]);
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
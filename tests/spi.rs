@@ -0,0 +1,83 @@
+extern crate ruspirate;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ruspirate::BusPirate;
+use ruspirate::sim::{Mode, Simulator, SpiDevice};
+use ruspirate::spi::{BusSettings, Config, SnifferFilter, Speed};
+
+// Answers each byte with the byte plus one, and remembers what it got.
+#[derive(Default)]
+struct Echo {
+    received: Vec<u8>,
+    selected: bool
+}
+
+impl SpiDevice for Echo {
+    fn select(&mut self) {
+        self.selected = true;
+    }
+
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        self.received.push(mosi);
+        mosi.wrapping_add(1)
+    }
+
+    fn deselect(&mut self) {
+        self.selected = false;
+    }
+}
+
+#[test]
+fn transfer() {
+    let echo = Rc::new(RefCell::new(Echo::default()));
+    let mut sim = Simulator::new();
+    sim.set_spi_device(Box::new(echo.clone()));
+    {
+        let mut spi = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_spi_mode().unwrap();
+        spi.configure(&BusSettings::new(Speed::Hz1000000, Config::default(),
+                                        true, false, false, true)).unwrap();
+        spi.cs_low().unwrap();
+        assert!(echo.borrow().selected);
+        let sent: Vec<u8> = (0..40).collect();
+        let received: Vec<u8> = (1..41).collect();
+        assert_eq!(spi.transfer(&sent).unwrap(), received);
+        spi.cs_high().unwrap();
+        assert!(!echo.borrow().selected);
+        assert_eq!(echo.borrow().received, sent);
+    }
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn write_then_read() {
+    let mut sim = Simulator::new();
+    sim.set_spi_device(Box::new(Rc::new(RefCell::new(Echo::default()))));
+    let mut spi = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_spi_mode().unwrap();
+    // The read half clocks out 0xFF.
+    assert_eq!(spi.write_then_read(&[0x9F], 3).unwrap(), vec![0, 0, 0]);
+    assert!(spi.write_then_read(&[0; 5000], 3).is_err());
+}
+
+#[test]
+fn bulk_transfer_count() {
+    let mut sim = Simulator::new();
+    let mut spi = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_spi_mode().unwrap();
+    assert!(spi.bulk_transfer(&[]).is_err());
+    assert!(spi.bulk_transfer(&[0; 17]).is_err());
+    // Nothing was sent, so the connection is still in step.
+    spi.test().unwrap();
+}
+
+#[test]
+fn sniff_quiet_bus() {
+    let mut sim = Simulator::new();
+    let mut spi = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_spi_mode().unwrap();
+    assert!(spi.sniff(SnifferFilter::CsLow).unwrap().next().is_none());
+    spi.test().unwrap();
+}
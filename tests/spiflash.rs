@@ -0,0 +1,183 @@
+extern crate ruspirate;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ruspirate::BusPirate;
+use ruspirate::sim::{Simulator, SpiDevice};
+use ruspirate::spiflash::{EraseType, Flash, FlashError, Geometry, Status};
+
+const SIZE: usize = 65536;
+const WEL: u8 = 0b0000_0010;
+
+// A 512Kbit W25X05-alike with an SFDP table: 4K, 32K and 64K erases
+// and 256 byte pages. Only what the driver uses is there.
+struct SimFlash {
+    mem: Vec<u8>,
+    status: u8,
+    sfdp: Vec<u8>,
+    cmd: Vec<u8>,
+    // (opcode, address) of every erase, in order.
+    erases: Vec<(u8, usize)>
+}
+
+impl SimFlash {
+    fn new() -> Self {
+        // SFDP header, one parameter header pointing at the basic
+        // flash parameter table at 0x10.
+        let mut sfdp = vec![b'S', b'F', b'D', b'P', 0x06, 0x01, 0x00, 0xFF,
+                            0x00, 0x06, 0x01, 11, 0x10, 0x00, 0x00, 0xFF];
+        let bfpt: [u32; 11] = [0x0000_2001, SIZE as u32 * 8 - 1, 0, 0, 0, 0, 0,
+                               0x520F_200C, 0x0000_D810, 0, 0x0000_0080];
+        for dword in &bfpt {
+            sfdp.extend(&dword.to_le_bytes());
+        }
+        SimFlash { mem: vec![0xFF; SIZE], status: 0, sfdp: sfdp, cmd: vec![], erases: vec![] }
+    }
+
+    fn addr(&self) -> usize {
+        (self.cmd[1] as usize) << 16 | (self.cmd[2] as usize) << 8 | self.cmd[3] as usize
+    }
+
+    fn erase(&mut self, opcode: u8, addr: usize, size: usize) {
+        let start = addr & !(size - 1);
+        for byte in &mut self.mem[start..start + size] {
+            *byte = 0xFF;
+        }
+        self.erases.push((opcode, start));
+    }
+}
+
+impl SpiDevice for SimFlash {
+    fn select(&mut self) {
+        self.cmd.clear();
+    }
+
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        self.cmd.push(mosi);
+        let n = self.cmd.len() - 1;
+        match self.cmd[0] {
+            0x9F if n >= 1 => [0xEF, 0x40, 0x10][(n - 1) % 3],
+            0x05 if n >= 1 => self.status,
+            // Address then a dummy byte.
+            0x5A if n >= 5 => *self.sfdp.get(self.addr() + n - 5).unwrap_or(&0xFF),
+            0x0B if n >= 5 => self.mem[(self.addr() + n - 5) % SIZE],
+            _ => 0xFF
+        }
+    }
+
+    fn deselect(&mut self) {
+        let cmd = self.cmd.clone();
+        if cmd.is_empty() {
+            return;
+        }
+        if cmd[0] == 0x06 {
+            self.status |= WEL;
+            return;
+        }
+        if self.status & WEL == 0 {
+            return;
+        }
+        let addr = if cmd.len() >= 4 { self.addr() } else { 0 };
+        match cmd[0] {
+            0x01 => self.status = cmd[1] & 0b1011_1100,
+            0x02 => {
+                // Programming wraps at the end of the page.
+                for (i, byte) in cmd[4..].iter().enumerate() {
+                    self.mem[(addr & !0xFF) | ((addr + i) & 0xFF)] &= *byte;
+                }
+            }
+            0x20 => self.erase(0x20, addr, 4096),
+            0x52 => self.erase(0x52, addr, 32768),
+            0xD8 => self.erase(0xD8, addr, 65536),
+            0xC7 => self.erase(0xC7, 0, SIZE),
+            _ => return
+        }
+        self.status &= !WEL;
+    }
+}
+
+#[test]
+fn probe() {
+    let mut sim = Simulator::new();
+    sim.set_spi_device(Box::new(SimFlash::new()));
+    let mut spi = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_spi_mode().unwrap();
+    let flash = Flash::new(&mut spi).unwrap();
+    assert_eq!(flash.id().to_string(), "ef4010");
+    let geometry = flash.geometry();
+    assert_eq!(geometry.size, SIZE);
+    assert_eq!(geometry.page_size, 256);
+    assert_eq!(geometry.erase, vec![EraseType { size: 4096, opcode: 0x20 },
+                                    EraseType { size: 32768, opcode: 0x52 },
+                                    EraseType { size: 65536, opcode: 0xD8 }]);
+}
+
+#[test]
+fn garbage_erase_size() {
+    // An erase size of 2^64 bytes.
+    let bfpt = [0x0000_2001, SIZE as u32 * 8 - 1, 0, 0, 0, 0, 0, 0x0000_2040, 0];
+    let err = Geometry::from_bfpt(&bfpt).unwrap_err();
+    match err.downcast_ref::<FlashError>() {
+        Some(&FlashError::InvalidSfdp { .. }) => (),
+        _ => panic!("{:?}", err)
+    }
+}
+
+#[test]
+fn unprotect() {
+    let chip = Rc::new(RefCell::new(SimFlash::new()));
+    chip.borrow_mut().status = 0b0001_1100;
+    let mut sim = Simulator::new();
+    sim.set_spi_device(Box::new(chip.clone()));
+    let mut spi = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_spi_mode().unwrap();
+    let mut flash = Flash::new(&mut spi).unwrap();
+    assert!(flash.status().unwrap().intersects(Status::PROTECT));
+    flash.unprotect().unwrap();
+    assert!(!flash.status().unwrap().intersects(Status::PROTECT));
+}
+
+#[test]
+fn write() {
+    let chip = Rc::new(RefCell::new(SimFlash::new()));
+    let mut sim = Simulator::new();
+    sim.set_spi_device(Box::new(chip.clone()));
+    let mut spi = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_spi_mode().unwrap();
+    let mut flash = Flash::new(&mut spi).unwrap();
+    let data: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+    flash.write(100, &data).unwrap();
+    flash.verify(100, &data).unwrap();
+    assert_eq!(flash.read(0, 100).unwrap(), vec![0xFF; 100]);
+
+    // Writing what's already there doesn't erase anything.
+    let erases = chip.borrow().erases.len();
+    flash.write(100, &data).unwrap();
+    assert_eq!(chip.borrow().erases.len(), erases);
+
+    // Changing one byte keeps the rest of its sector.
+    flash.write(200, &[0xFF]).unwrap();
+    let mut expected = data.clone();
+    expected[100] = 0xFF;
+    flash.verify(100, &expected).unwrap();
+}
+
+#[test]
+fn erase() {
+    let chip = Rc::new(RefCell::new(SimFlash::new()));
+    let mut sim = Simulator::new();
+    sim.set_spi_device(Box::new(chip.clone()));
+    let mut spi = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+        .enter_spi_mode().unwrap();
+    let mut flash = Flash::new(&mut spi).unwrap();
+    // Not on a sector boundary.
+    assert!(flash.erase(100, 4096).is_err());
+    // The biggest erases that fit.
+    flash.erase(0, SIZE - 4096).unwrap();
+    assert_eq!(chip.borrow().erases,
+               vec![(0x52, 0), (0x20, 32768), (0x20, 36864), (0x20, 40960),
+                    (0x20, 45056), (0x20, 49152), (0x20, 53248), (0x20, 57344)]);
+    flash.erase(0, SIZE).unwrap();
+    assert_eq!(chip.borrow().erases.last(), Some(&(0xC7, 0)));
+}
@@ -0,0 +1,28 @@
+extern crate ruspirate;
+extern crate serial;
+
+mod common;
+
+use ruspirate::{BusPirate, Transport};
+
+use common::Canned;
+
+const BANNER: &[u8] = b"#\r\nRESET\r\n\r\nBus Pirate v4\r\nFirmware v6.1 r1676\r\nHiZ>";
+
+#[test]
+fn read_vsn_through_a_borrowed_transport() {
+    let mut port = Canned::new(BANNER);
+    let vsn = BusPirate::new(&mut port).read_vsn().unwrap();
+    assert_eq!(vsn, "\nBus Pirate v4\nFirmware v6.1 r1676");
+    // The prompt escape goes out first, and the read ends once the
+    // transport times out.
+    assert_eq!(port.sent, b"\n\n\n\n\n\n\n\n\n\n#\n".to_vec());
+    assert!(port.replies.is_empty());
+}
+
+#[test]
+fn read_vsn_through_a_boxed_transport() {
+    let port: Box<dyn Transport> = Box::new(Canned::new(BANNER));
+    let vsn = BusPirate::new(port).read_vsn().unwrap();
+    assert_eq!(vsn, "\nBus Pirate v4\nFirmware v6.1 r1676");
+}
@@ -0,0 +1,50 @@
+extern crate ruspirate;
+
+use std::io::{Read, Write};
+
+use ruspirate::BusPirate;
+use ruspirate::sim::{Mode, Simulator};
+use ruspirate::uart::{brg_for_baud, BusSettings, Config, Speed};
+
+#[test]
+fn baud_rate_generator() {
+    assert_eq!(brg_for_baud(115200), 34);
+    assert_eq!(brg_for_baud(9600), 416);
+}
+
+#[test]
+fn write() {
+    let mut sim = Simulator::new();
+    let sent: Vec<u8> = (0..40).collect();
+    {
+        let mut uart = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_uart_mode().unwrap();
+        uart.configure(&BusSettings::new(Speed::Baud115200, Config::default(),
+                                         true, false, false, false)).unwrap();
+        uart.set_custom_baud(250000).unwrap();
+        uart.write_all(&sent).unwrap();
+        uart.test().unwrap();
+    }
+    assert_eq!(sim.uart_sent(), &sent[..]);
+    assert_eq!(sim.mode(), Mode::BBIO);
+}
+
+#[test]
+fn echo_rx() {
+    let mut sim = Simulator::new();
+    sim.uart_receive(b"hello");
+    {
+        let mut uart = BusPirate::new(&mut sim).enter_bio_mode().unwrap()
+            .enter_uart_mode().unwrap();
+        uart.echo_rx(true).unwrap();
+        // Acks can't be told apart from echoed bytes.
+        assert!(uart.bulk_write(b"x").is_err());
+        uart.echo_rx(false).unwrap();
+        let mut received = [0; 5];
+        uart.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"hello");
+        uart.bulk_write(b"x").unwrap();
+    }
+    assert_eq!(sim.uart_sent(), b"x");
+    assert_eq!(sim.mode(), Mode::BBIO);
+}